@@ -0,0 +1,263 @@
+/*
+ * Zero-copy Portable Executable parser
+ *
+ * (C) Copyright 2016 Jethro G. Beekman
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; version 2
+ * of the License.
+ */
+
+//! API set schema parsing, as found in the `.apiset` section of
+//! apisetschema.dll. Schema versions 2 (Windows 7, 8), 4 (Windows 8.1) and 6
+//! (Windows 10) are supported.
+
+use std::mem::size_of;
+
+use super::{Pe,Error,Result,AsOsStr};
+use super::utility::{FP,URP,FPRef,RefSafe};
+
+#[repr(packed)]
+struct NamespaceV2 {
+	version: u32,
+	count: u32,
+}
+unsafe impl RefSafe for NamespaceV2 {}
+
+#[repr(packed)]
+struct NamespaceEntryV2 {
+	name_offset: u32,
+	name_length: u32,
+	data_offset: u32, // FP<ValueArrayV2>
+}
+unsafe impl RefSafe for NamespaceEntryV2 {}
+
+#[repr(packed)]
+struct ValueArrayV2 {
+	count: u32,
+}
+unsafe impl RefSafe for ValueArrayV2 {}
+
+#[repr(packed)]
+struct ValueEntryV2 {
+	name_offset: u32,
+	name_length: u32,
+	value_offset: u32,
+	value_length: u32,
+}
+unsafe impl RefSafe for ValueEntryV2 {}
+
+#[repr(packed)]
+#[allow(dead_code)]
+struct NamespaceV4 {
+	version: u32,
+	size: u32,
+	flags: u32,
+	count: u32,
+}
+unsafe impl RefSafe for NamespaceV4 {}
+
+#[repr(packed)]
+#[allow(dead_code)]
+struct NamespaceEntryV4 {
+	flags: u32,
+	name_offset: u32,
+	name_length: u32,
+	alias_offset: u32,
+	alias_length: u32,
+	data_offset: u32, // FP<ValueArrayV4>
+}
+unsafe impl RefSafe for NamespaceEntryV4 {}
+
+#[repr(packed)]
+#[allow(dead_code)]
+struct ValueArrayV4 {
+	flags: u32,
+	count: u32,
+}
+unsafe impl RefSafe for ValueArrayV4 {}
+
+// Also used by version 6
+#[repr(packed)]
+#[allow(dead_code)]
+struct ValueEntryV4 {
+	flags: u32,
+	name_offset: u32,
+	name_length: u32,
+	value_offset: u32,
+	value_length: u32,
+}
+unsafe impl RefSafe for ValueEntryV4 {}
+
+#[repr(packed)]
+#[allow(dead_code)]
+struct NamespaceV6 {
+	version: u32,
+	size: u32,
+	flags: u32,
+	count: u32,
+	entry_offset: u32,
+	hash_offset: u32,
+	hash_factor: u32,
+}
+unsafe impl RefSafe for NamespaceV6 {}
+
+#[repr(packed)]
+#[allow(dead_code)]
+struct NamespaceEntryV6 {
+	flags: u32,
+	name_offset: u32,
+	name_length: u32,
+	hashed_length: u32,
+	value_offset: u32, // FP<[ValueEntryV4]>
+	value_count: u32,
+}
+unsafe impl RefSafe for NamespaceEntryV6 {}
+
+#[derive(Debug,Clone)]
+pub struct ApiSetHost {
+	/// The importing module this host applies to, `None` for the default host
+	pub importer: Option<String>,
+	pub host: String,
+}
+
+#[derive(Debug,Clone)]
+pub struct ApiSet {
+	/// The name as stored in the schema. Before version 6, this does not
+	/// include the `api-` prefix.
+	pub name: String,
+	pub hosts: Vec<ApiSetHost>,
+	key: String,
+	hashed: bool,
+}
+
+#[derive(Debug,Clone)]
+pub struct ApiSetSchema {
+	version: u32,
+	sets: Vec<ApiSet>,
+}
+
+fn read_string(data: &[u8], offset: u32, length: u32) -> Result<String> {
+	let chars=try!(data.ref_slice_at::<u16>(FP::new(offset),length/2));
+	Ok(String::from_utf16_lossy(chars))
+}
+
+fn read_host(data: &[u8], name_offset: u32, name_length: u32, value_offset: u32, value_length: u32) -> Result<ApiSetHost> {
+	let importer=if name_length==0 { None } else { Some(try!(read_string(data,name_offset,name_length))) };
+	Ok(ApiSetHost{importer:importer,host:try!(read_string(data,value_offset,value_length))})
+}
+
+fn read_hosts_v4(data: &[u8], values: &[ValueEntryV4]) -> Result<Vec<ApiSetHost>> {
+	values.iter().map(|v|read_host(data,v.name_offset,v.name_length,v.value_offset,v.value_length)).collect()
+}
+
+/// Strip the `.dll` extension and convert to lowercase
+fn normalize(module: &str) -> String {
+	let module=module.to_lowercase();
+	if module.ends_with(".dll") {
+		module[..module.len()-4].to_owned()
+	} else {
+		module
+	}
+}
+
+impl ApiSet {
+	fn new(name: String, hashed_length: Option<u32>, hosts: Vec<ApiSetHost>) -> ApiSet {
+		let key=match hashed_length {
+			Some(len) => name.chars().take(len as usize/2).collect::<String>().to_lowercase(),
+			None => name.to_lowercase(),
+		};
+		ApiSet{name:name,hosts:hosts,key:key,hashed:hashed_length.is_some()}
+	}
+
+	/// `module` must be normalized
+	fn matches(&self, module: &str) -> bool {
+		if self.hashed {
+			// The last version number is ignored
+			module.rfind('-').map_or(false,|pos|module[..pos]==self.key)
+		} else {
+			module==self.key || ["api-","ext-"].iter().any(|prefix|module.starts_with(prefix) && module[prefix.len()..]==self.key)
+		}
+	}
+}
+
+impl ApiSetSchema {
+	/// Parse the contents of the `.apiset` section
+	pub fn parse(data: &[u8]) -> Result<ApiSetSchema> {
+		let version=try!(data.ref_at::<NamespaceV2>(FP::new(0))).version;
+		let mut sets=vec![];
+		match version {
+			2 => {
+				let ns=try!(data.ref_at::<NamespaceV2>(FP::new(0)));
+				let entries=try!(data.ref_slice_at::<NamespaceEntryV2>(FP::new(size_of::<NamespaceV2>() as u32),ns.count));
+				for entry in entries {
+					let array=try!(data.ref_at::<ValueArrayV2>(FP::new(entry.data_offset)));
+					let values=try!(data.ref_slice_at::<ValueEntryV2>(FP::new(entry.data_offset.wrapping_add(size_of::<ValueArrayV2>() as u32)),array.count));
+					let hosts=try!(values.iter().map(|v|read_host(data,v.name_offset,v.name_length,v.value_offset,v.value_length)).collect());
+					sets.push(ApiSet::new(try!(read_string(data,entry.name_offset,entry.name_length)),None,hosts));
+				}
+			},
+			4 => {
+				let ns=try!(data.ref_at::<NamespaceV4>(FP::new(0)));
+				let entries=try!(data.ref_slice_at::<NamespaceEntryV4>(FP::new(size_of::<NamespaceV4>() as u32),ns.count));
+				for entry in entries {
+					let array=try!(data.ref_at::<ValueArrayV4>(FP::new(entry.data_offset)));
+					let values=try!(data.ref_slice_at::<ValueEntryV4>(FP::new(entry.data_offset.wrapping_add(size_of::<ValueArrayV4>() as u32)),array.count));
+					sets.push(ApiSet::new(try!(read_string(data,entry.name_offset,entry.name_length)),None,try!(read_hosts_v4(data,values))));
+				}
+			},
+			6 => {
+				let ns=try!(data.ref_at::<NamespaceV6>(FP::new(0)));
+				let entries=try!(data.ref_slice_at::<NamespaceEntryV6>(FP::new(ns.entry_offset),ns.count));
+				for entry in entries {
+					let values=try!(data.ref_slice_at::<ValueEntryV4>(FP::new(entry.value_offset),entry.value_count));
+					sets.push(ApiSet::new(try!(read_string(data,entry.name_offset,entry.name_length)),Some(entry.hashed_length),try!(read_hosts_v4(data,values))));
+				}
+			},
+			_ => return Err(Error::UnsupportedVersion),
+		}
+		Ok(ApiSetSchema{version:version,sets:sets})
+	}
+
+	/// Parse the `.apiset` section of apisetschema.dll
+	pub fn from_pe(pe: &Pe) -> Result<ApiSetSchema> {
+		let section=try!(pe.get_sections().iter().find(|section|section.name.as_os_str()==".apiset").ok_or(Error::DirectoryMissing));
+		ApiSetSchema::parse(try!(pe.ref_slice_at_fp(section.pointer_to_raw_data,section.size_of_raw_data)))
+	}
+
+	pub fn get_version(&self) -> u32 {
+		self.version
+	}
+
+	pub fn get_api_sets(&self) -> &[ApiSet] {
+		&self.sets
+	}
+
+	/// Find the API set that a module name refers to, the same way the loader
+	/// does. The `.dll` extension is optional.
+	pub fn find(&self, module: &str) -> Option<&ApiSet> {
+		let module=normalize(module);
+		self.sets.iter().find(|set|set.matches(&module))
+	}
+
+	/// Find the host DLL for a virtual module name. An importer-specific host
+	/// is used if one exists for `importer`, otherwise the default host.
+	/// Returns `None` if `module` is not an API set or the API set has no
+	/// host.
+	pub fn resolve(&self, module: &str, importer: Option<&str>) -> Option<&str> {
+		let hosts=match self.find(module) {
+			Some(set) => set.hosts.iter().filter(|host|!host.host.is_empty()),
+			None => return None,
+		};
+		let mut default=None;
+		for host in hosts {
+			match (&host.importer,importer) {
+				(&Some(ref host_importer),Some(importer)) if normalize(host_importer)==normalize(importer) => return Some(&host.host),
+				(&Some(_),_) => {},
+				(&None,_) => default=default.or(Some(&*host.host)),
+			}
+		}
+		default
+	}
+}
@@ -0,0 +1,275 @@
+/*
+ * Zero-copy Portable Executable parser
+ *
+ * (C) Copyright 2016 Jethro G. Beekman
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; version 2
+ * of the License.
+ */
+
+//! Transitive dependency resolution over a set of modules.
+
+use std::collections::{HashMap,HashSet,VecDeque};
+
+use super::apiset::ApiSetSchema;
+use super::{Pe,Error,Result,AsOsStr,CChar,ImportLookup,Exports,ExportAddress,Forwarder,ForwarderSymbol};
+
+/// Forwarder chains longer than this are considered to be loops
+const MAX_FORWARDER_DEPTH: usize = 32;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum DependencyKind {
+	Import,
+	DelayImport,
+	/// The module forwards one of its exports that is used to the dependency
+	Forwarder,
+	/// The module is an API set that is hosted by the dependency
+	ApiSet,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub struct Dependency {
+	pub importer: String,
+	pub module: String,
+	pub kind: DependencyKind,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub enum Symbol {
+	Name(String),
+	Ordinal(u32),
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum MissingReason {
+	/// The module does not export the symbol
+	NotExported,
+	/// The export directory or a forwarder string of the module is malformed
+	MalformedExports,
+	/// The forwarder chain is longer than `MAX_FORWARDER_DEPTH`, which
+	/// probably means it is a loop
+	ForwarderDepthExceeded,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub struct MissingSymbol {
+	/// The module that imports or forwards the symbol
+	pub importer: String,
+	pub module: String,
+	pub symbol: Symbol,
+	pub reason: MissingReason,
+}
+
+/// The result of dependency resolution. All module names are lowercase. API
+/// sets appear only as dependencies, not as modules.
+#[derive(Debug,Default)]
+pub struct DependencyGraph {
+	/// All modules that were found, in breadth-first order starting with the
+	/// root module
+	pub modules: Vec<String>,
+	pub dependencies: Vec<Dependency>,
+	/// Modules that are depended upon but that are not in the search path
+	pub missing_modules: Vec<String>,
+	/// Symbols that could not be found in modules that are in the search path
+	pub missing_symbols: Vec<MissingSymbol>,
+}
+
+/// A search path of modules, keyed by file name.
+pub struct Resolver<'pe,'data: 'pe> {
+	modules: HashMap<String,&'pe Pe<'data>>,
+	api_sets: Option<ApiSetSchema>,
+}
+
+struct Walk<'r,'pe: 'r,'data: 'pe> {
+	resolver: &'r Resolver<'pe,'data>,
+	graph: DependencyGraph,
+	seen_modules: HashSet<String>,
+	seen_dependencies: HashSet<Dependency>,
+	queue: VecDeque<(String,&'pe Pe<'data>)>,
+	exports: HashMap<String,ModuleExports<'pe,'data>>,
+}
+
+/// The export directory of a module, parsed once per walk
+#[derive(Clone)]
+enum ModuleExports<'pe,'data: 'pe> {
+	Exports(Exports<'pe,'data>),
+	Missing,
+	Malformed,
+}
+
+fn module_name(name: &[CChar]) -> String {
+	name.as_os_str().to_string_lossy().to_lowercase()
+}
+
+fn forwarder_symbol(symbol: ForwarderSymbol) -> Symbol {
+	match symbol {
+		ForwarderSymbol::Name(name) => Symbol::Name(name.as_os_str().to_string_lossy().into_owned()),
+		ForwarderSymbol::Ordinal(ordinal) => Symbol::Ordinal(ordinal),
+	}
+}
+
+/// Returns the forwarder if the symbol is forwarded
+fn lookup_export<'pe,'data: 'pe>(exports: &Exports<'pe,'data>, symbol: &Symbol) -> Result<Option<Forwarder<'data>>> {
+	let address=match *symbol {
+		Symbol::Name(ref name) => exports.lookup_symbol(name),
+		Symbol::Ordinal(ordinal) => exports.lookup_ordinal(ordinal),
+	};
+	match try!(address) {
+		ExportAddress::Export(_) => Ok(None),
+		ExportAddress::Forwarder(rva) => exports.parse_forwarder(rva).map(Some),
+	}
+}
+
+impl<'pe,'data: 'pe> Resolver<'pe,'data> {
+	pub fn new() -> Resolver<'pe,'data> {
+		Resolver{modules:HashMap::new(),api_sets:None}
+	}
+
+	/// Add a module to the search path. `name` is the file name, including
+	/// the extension, and is matched case-insensitively.
+	pub fn add_module(&mut self, name: &str, pe: &'pe Pe<'data>) {
+		self.modules.insert(name.to_lowercase(),pe);
+	}
+
+	/// Resolve imports from API sets to their hosts using `schema`
+	pub fn set_api_set_schema(&mut self, schema: ApiSetSchema) {
+		self.api_sets=Some(schema);
+	}
+
+	pub fn get_module(&self, name: &str) -> Option<&'pe Pe<'data>> {
+		self.modules.get(&name.to_lowercase()).cloned()
+	}
+
+	/// Walk the imports, delay imports and used export forwarders, starting
+	/// from `root`. `root` does not need to be in the search path.
+	pub fn resolve(&self, root_name: &str, root: &'pe Pe<'data>) -> Result<DependencyGraph> {
+		let mut walk=Walk{
+			resolver:self,
+			graph:DependencyGraph::default(),
+			seen_modules:HashSet::new(),
+			seen_dependencies:HashSet::new(),
+			queue:VecDeque::new(),
+			exports:HashMap::new(),
+		};
+		let root_name=root_name.to_lowercase();
+		walk.seen_modules.insert(root_name.clone());
+		walk.graph.modules.push(root_name.clone());
+		walk.queue.push_back((root_name,root));
+		while let Some((name,pe))=walk.queue.pop_front() {
+			try!(walk.visit(&name,pe));
+		}
+		Ok(walk.graph)
+	}
+}
+
+impl<'r,'pe: 'r,'data: 'pe> Walk<'r,'pe,'data> {
+	fn visit(&mut self, name: &str, pe: &'pe Pe<'data>) -> Result<()> {
+		match pe.get_imports() {
+			Ok(imports) => for import in imports {
+				let import=try!(import);
+				try!(self.visit_import(name,import.get_name(),DependencyKind::Import,import.get_lookup_table()));
+			},
+			Err(Error::DirectoryMissing) => {},
+			Err(e) => return Err(e),
+		}
+		match pe.get_delay_imports() {
+			Ok(imports) => for import in imports {
+				let import=try!(import);
+				try!(self.visit_import(name,import.get_name(),DependencyKind::DelayImport,import.get_lookup_table()));
+			},
+			Err(Error::DirectoryMissing) => {},
+			Err(e) => return Err(e),
+		}
+		Ok(())
+	}
+
+	fn visit_import<I: Iterator<Item=Result<ImportLookup<'data>>>>(&mut self, importer: &str, dll: &[CChar], kind: DependencyKind, lookups: I) -> Result<()> {
+		let module=self.add_dependency(importer,&module_name(dll),kind);
+		for lookup in lookups {
+			let symbol=match try!(lookup) {
+				ImportLookup::Ordinal(ordinal) => Symbol::Ordinal(ordinal as u32),
+				ImportLookup::HintName(_,name) => Symbol::Name(name.as_os_str().to_string_lossy().into_owned()),
+			};
+			self.resolve_symbol(importer.to_owned(),module.clone(),symbol);
+		}
+		Ok(())
+	}
+
+	/// Record a dependency, and queue the module if it hasn't been seen yet.
+	/// Returns the name of the module that provides the symbols, which is
+	/// different from `module` if it is an API set.
+	fn add_dependency(&mut self, importer: &str, module: &str, kind: DependencyKind) -> String {
+		self.add_edge(importer,module,kind);
+		let host=self.resolver.api_sets.as_ref().and_then(|schema|schema.resolve(module,Some(importer))).map(str::to_lowercase);
+		if let Some(host)=host {
+			self.add_edge(module,&host,DependencyKind::ApiSet);
+			self.add_module(&host);
+			return host;
+		}
+		self.add_module(module);
+		module.to_owned()
+	}
+
+	fn add_edge(&mut self, importer: &str, module: &str, kind: DependencyKind) {
+		let dependency=Dependency{importer:importer.to_owned(),module:module.to_owned(),kind:kind};
+		if self.seen_dependencies.insert(dependency.clone()) {
+			self.graph.dependencies.push(dependency);
+		}
+	}
+
+	fn add_module(&mut self, module: &str) {
+		if self.seen_modules.insert(module.to_owned()) {
+			match self.resolver.get_module(module) {
+				Some(pe) => {
+					self.graph.modules.push(module.to_owned());
+					self.queue.push_back((module.to_owned(),pe));
+				},
+				None => self.graph.missing_modules.push(module.to_owned()),
+			}
+		}
+	}
+
+	fn get_exports(&mut self, module: &str, pe: &'pe Pe<'data>) -> ModuleExports<'pe,'data> {
+		if let Some(exports)=self.exports.get(module) {
+			return exports.clone();
+		}
+		let exports=match pe.get_exports() {
+			Ok(exports) => ModuleExports::Exports(exports),
+			Err(Error::DirectoryMissing) => ModuleExports::Missing,
+			Err(_) => ModuleExports::Malformed,
+		};
+		self.exports.insert(module.to_owned(),exports.clone());
+		exports
+	}
+
+	/// Find `symbol` in `module`, following forwarders. Symbols in missing
+	/// modules are not reported, since the module is already reported.
+	fn resolve_symbol(&mut self, mut importer: String, mut module: String, mut symbol: Symbol) {
+		let mut reason=MissingReason::ForwarderDepthExceeded;
+		for _ in 0..MAX_FORWARDER_DEPTH {
+			let pe=match self.resolver.get_module(&module) {
+				Some(pe) => pe,
+				None => return,
+			};
+			let exports=match self.get_exports(&module,pe) {
+				ModuleExports::Exports(exports) => exports,
+				ModuleExports::Missing => { reason=MissingReason::NotExported; break },
+				ModuleExports::Malformed => { reason=MissingReason::MalformedExports; break },
+			};
+			let forwarder=match lookup_export(&exports,&symbol) {
+				Ok(None) => return,
+				Ok(Some(forwarder)) => forwarder,
+				Err(Error::SymbolNotFound) => { reason=MissingReason::NotExported; break },
+				Err(_) => { reason=MissingReason::MalformedExports; break },
+			};
+			let target=forwarder.get_dll_name().to_string_lossy().to_lowercase();
+			let target=self.add_dependency(&module,&target,DependencyKind::Forwarder);
+			importer=module;
+			module=target;
+			symbol=forwarder_symbol(forwarder.symbol);
+		}
+		self.graph.missing_symbols.push(MissingSymbol{importer:importer,module:module,symbol:symbol,reason:reason});
+	}
+}
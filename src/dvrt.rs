@@ -0,0 +1,324 @@
+/*
+ * Zero-copy Portable Executable parser
+ *
+ * (C) Copyright 2016 Jethro G. Beekman
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; version 2
+ * of the License.
+ */
+
+//! Dynamic value relocation table (DVRT) parsing. The DVRT is referenced from
+//! the load config directory and describes code and data that the loader or
+//! kernel patches at runtime, such as import and indirect control transfers
+//! and ARM64X alternate views. Versions 1 and 2 are supported.
+
+use std::mem::size_of;
+
+use super::{Error,Result,RVA};
+use super::types::*;
+use super::utility::{FP,URP,URPConvert,FPRef,read_u16,read_u32};
+
+fn sub_slice(data: &[u8], pos: usize, len: usize) -> Result<&[u8]> {
+	if pos>data.len() || len>data.len()-pos {
+		return Err(Error::InvalidSize);
+	}
+	Ok(&data[pos..pos+len])
+}
+
+pub struct DynamicRelocationIter<'data> {
+	data: &'data [u8],
+	version: u32,
+	is64: bool,
+	machine: Machine,
+}
+
+/// A dynamic relocation header and its fixup information, which applies to
+/// all fixups for one symbol
+#[derive(Debug,Clone,Copy)]
+pub struct DynamicRelocation<'data> {
+	/// One of `dynamic_relocation_symbol`, or the VA of the value stored by
+	/// each fixup
+	pub symbol: u64,
+	/// Always 0 in version 1
+	pub symbol_group: u32,
+	/// Always 0 in version 1
+	pub flags: u32,
+	/// Symbol-specific header data, always empty in version 1
+	pub header: &'data [u8],
+	/// Fixups, in blocks that start with a `RelocationBlock` header
+	pub fixup_info: &'data [u8],
+	machine: Machine,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct DynamicFixup<'data> {
+	pub rva: RVA<()>,
+	pub kind: DynamicFixupKind<'data>,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum DynamicFixupKind<'data> {
+	/// A regular base relocation of the symbol value. Used for unknown
+	/// symbols and return flow guard prologues and epilogues.
+	Relocation(RelocationType),
+	ImportControlTransfer {
+		indirect_call: bool,
+		iat_index: u32,
+	},
+	IndirControlTransfer {
+		indirect_call: bool,
+		rex_w_prefix: bool,
+		cfg_check: bool,
+	},
+	SwitchTableBranch {
+		register_number: u8,
+	},
+	Arm64X(Arm64XFixup<'data>),
+	Arm64KernelImportCallTransfer {
+		indirect_call: bool,
+		register_index: u8,
+		import_type: bool,
+		iat_index: u16,
+	},
+}
+
+/// A change applied to get the alternate view of an ARM64X image
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Arm64XFixup<'data> {
+	/// The number of bytes to clear
+	ZeroFill(u8),
+	/// The bytes to store
+	Value(&'data [u8]),
+	/// The value to add to the 32-bit value at the fixup location
+	Delta(i64),
+}
+
+/// The fixup information of a `FUNCTION_OVERRIDE` dynamic relocation
+#[derive(Debug,Clone)]
+pub struct FunctionOverrides<'data> {
+	pub overrides: Vec<FunctionOverride<'data>>,
+	pub bdd_version: u32,
+	pub bdd_nodes: &'data [BddNode],
+}
+
+#[derive(Debug,Clone)]
+pub struct FunctionOverride<'data> {
+	pub original_rva: RVA<()>,
+	pub bdd_offset: u32,
+	pub override_rvas: Vec<RVA<()>>,
+	/// The locations that refer to the original function
+	pub fixups: Vec<DynamicFixup<'data>>,
+}
+
+impl<'data> DynamicRelocationIter<'data> {
+	/// Parse a table that starts with a `DynamicRelocationTable` header.
+	/// `is64` specifies whether the symbols are 64-bit, as in PE32+ images.
+	pub fn new(data: &'data [u8], is64: bool, machine: Machine) -> Result<DynamicRelocationIter<'data>> {
+		let table=try!(data.ref_at::<DynamicRelocationTable>(FP::new(0)));
+		if table.version!=1 && table.version!=2 {
+			return Err(Error::UnsupportedVersion);
+		}
+		let entries=try!(sub_slice(data,size_of::<DynamicRelocationTable>(),table.size as usize));
+		Ok(DynamicRelocationIter{data:entries,version:table.version,is64:is64,machine:machine})
+	}
+
+	pub fn get_version(&self) -> u32 {
+		self.version
+	}
+
+	fn advance(&mut self) -> Result<DynamicRelocation<'data>> {
+		let data=self.data;
+		let (symbol,symbol_group,flags,header_size,fixup_info_size,header_start)=match (self.version,self.is64) {
+			(1,false) => {
+				let h=try!(data.ref_at::<DynamicRelocation32>(FP::new(0)));
+				let size=size_of::<DynamicRelocation32>();
+				(h.symbol as u64,0,0,size,h.base_reloc_size,size)
+			},
+			(1,true) => {
+				let h=try!(data.ref_at::<DynamicRelocation64>(FP::new(0)));
+				let size=size_of::<DynamicRelocation64>();
+				(h.symbol,0,0,size,h.base_reloc_size,size)
+			},
+			(_,false) => {
+				let h=try!(data.ref_at::<DynamicRelocation32V2>(FP::new(0)));
+				(h.symbol as u64,h.symbol_group,h.flags,h.header_size as usize,h.fixup_info_size,size_of::<DynamicRelocation32V2>())
+			},
+			(_,true) => {
+				let h=try!(data.ref_at::<DynamicRelocation64V2>(FP::new(0)));
+				(h.symbol,h.symbol_group,h.flags,h.header_size as usize,h.fixup_info_size,size_of::<DynamicRelocation64V2>())
+			},
+		};
+		if header_size<header_start {
+			return Err(Error::InvalidSize);
+		}
+		let header=try!(sub_slice(data,header_start,header_size-header_start));
+		let fixup_info=try!(sub_slice(data,header_size,fixup_info_size as usize));
+		self.data=&data[header_size+fixup_info_size as usize..];
+		Ok(DynamicRelocation{
+			symbol:symbol,
+			symbol_group:symbol_group,
+			flags:flags,
+			header:header,
+			fixup_info:fixup_info,
+			machine:self.machine,
+		})
+	}
+}
+
+impl<'data> Iterator for DynamicRelocationIter<'data> {
+	type Item=Result<DynamicRelocation<'data>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.data.is_empty() {
+			return None;
+		}
+		let result=self.advance();
+		if result.is_err() {
+			self.data=&[];
+		}
+		Some(result)
+	}
+}
+
+/// Split base relocation blocks into their page RVA and entries
+fn parse_blocks(data: &[u8]) -> Result<Vec<(RVA<()>,&[u8])>> {
+	let mut blocks=vec![];
+	let mut pos=0;
+	while pos<data.len() {
+		let block=try!(sub_slice(data,pos,size_of::<RelocationBlock>()));
+		let page_rva=RVA::new(try!(read_u32(block,0)));
+		let block_size=try!(read_u32(block,4)) as usize;
+		if block_size<size_of::<RelocationBlock>() {
+			return Err(Error::InvalidSize);
+		}
+		let entries=try!(sub_slice(data,pos+size_of::<RelocationBlock>(),block_size-size_of::<RelocationBlock>()));
+		blocks.push((page_rva,entries));
+		pos+=block_size;
+	}
+	Ok(blocks)
+}
+
+/// A zero 16-bit entry at the end of a block is padding
+fn is_padding(entries: &[u8], pos: usize, value: u16) -> bool {
+	value==0 && pos+2==entries.len()
+}
+
+fn parse_arm64x<'data>(entries: &'data [u8], pos: &mut usize, value: u16) -> Result<Arm64XFixup<'data>> {
+	let size=(value>>14) as usize;
+	match (value>>12)&3 {
+		0 => Ok(Arm64XFixup::ZeroFill(1<<size)),
+		1 => {
+			let bytes=try!(sub_slice(entries,*pos,1<<size));
+			*pos+=1<<size;
+			Ok(Arm64XFixup::Value(bytes))
+		},
+		2 => {
+			let scale=if size&2!=0 { 8 } else { 4 };
+			let delta=try!(read_u16(entries,*pos)) as i64*scale;
+			*pos+=2;
+			Ok(Arm64XFixup::Delta(if size&1!=0 { -delta } else { delta }))
+		},
+		_ => Err(Error::UnsupportedRelocation),
+	}
+}
+
+/// Decode the fixups in base relocation blocks with entries for `symbol`
+fn parse_fixups<'data>(data: &'data [u8], symbol: u64, machine: Machine) -> Result<Vec<DynamicFixup<'data>>> {
+	let mut fixups=vec![];
+	for (page_rva,entries) in try!(parse_blocks(data)) {
+		let mut pos=0;
+		while pos<entries.len() {
+			let (offset,kind)=match symbol {
+				dynamic_relocation_symbol::GUARD_IMPORT_CONTROL_TRANSFER => {
+					let value=try!(read_u32(entries,pos));
+					pos+=4;
+					(value&0xfff,DynamicFixupKind::ImportControlTransfer{
+						indirect_call:value&0x1000!=0,
+						iat_index:value>>13,
+					})
+				},
+				dynamic_relocation_symbol::ARM64_KERNEL_IMPORT_CALL_TRANSFER => {
+					let value=try!(read_u32(entries,pos));
+					pos+=4;
+					((value&0x3ff)*4,DynamicFixupKind::Arm64KernelImportCallTransfer{
+						indirect_call:value&0x400!=0,
+						register_index:((value>>11)&0x1f) as u8,
+						import_type:value&0x10000!=0,
+						iat_index:(value>>17) as u16,
+					})
+				},
+				_ => {
+					let value=try!(read_u16(entries,pos));
+					if is_padding(entries,pos,value) {
+						break;
+					}
+					pos+=2;
+					let offset=(value&0xfff) as u32;
+					match symbol {
+						dynamic_relocation_symbol::GUARD_INDIR_CONTROL_TRANSFER => (offset,DynamicFixupKind::IndirControlTransfer{
+							indirect_call:value&0x1000!=0,
+							rex_w_prefix:value&0x2000!=0,
+							cfg_check:value&0x4000!=0,
+						}),
+						dynamic_relocation_symbol::GUARD_SWITCHTABLE_BRANCH => (offset,DynamicFixupKind::SwitchTableBranch{
+							register_number:(value>>12) as u8,
+						}),
+						dynamic_relocation_symbol::ARM64X => (offset,DynamicFixupKind::Arm64X(try!(parse_arm64x(entries,&mut pos,value)))),
+						_ => match Relocation(value).decode(machine) {
+							(RelocationType::ABSOLUTE,_) => continue,
+							(rtype,_) => (offset,DynamicFixupKind::Relocation(rtype)),
+						},
+					}
+				},
+			};
+			fixups.push(DynamicFixup{rva:page_rva.offset(offset),kind:kind});
+		}
+	}
+	Ok(fixups)
+}
+
+impl<'data> DynamicRelocation<'data> {
+	/// Decode the fixups according to the symbol. `FUNCTION_OVERRIDE`
+	/// relocations are not supported, use `get_function_overrides` instead.
+	pub fn get_fixups(&self) -> Result<Vec<DynamicFixup<'data>>> {
+		if self.symbol==dynamic_relocation_symbol::FUNCTION_OVERRIDE {
+			return Err(Error::UnsupportedRelocation);
+		}
+		parse_fixups(self.fixup_info,self.symbol,self.machine)
+	}
+
+	pub fn get_function_overrides(&self) -> Result<FunctionOverrides<'data>> {
+		if self.symbol!=dynamic_relocation_symbol::FUNCTION_OVERRIDE {
+			return Err(Error::UnsupportedRelocation);
+		}
+		let data=self.fixup_info;
+		let overrides_size=try!(read_u32(data,0)) as usize;
+		let overrides_data=try!(sub_slice(data,4,overrides_size));
+		let mut overrides=vec![];
+		let mut pos=0;
+		while pos<overrides_data.len() {
+			let h=try!(overrides_data.ref_at::<FunctionOverrideDynamicRelocation>(FP::new(pos as u32)));
+			pos+=size_of::<FunctionOverrideDynamicRelocation>();
+			let rvas=try!(sub_slice(overrides_data,pos,h.rva_size as usize));
+			pos+=rvas.len();
+			let relocs=try!(sub_slice(overrides_data,pos,h.base_reloc_size as usize));
+			pos+=relocs.len();
+			let mut override_rvas=vec![];
+			for i in 0..rvas.len()/4 {
+				override_rvas.push(RVA::new(try!(read_u32(rvas,i*4))));
+			}
+			overrides.push(FunctionOverride{
+				original_rva:h.original_rva,
+				bdd_offset:h.bdd_offset,
+				override_rvas:override_rvas,
+				fixups:try!(parse_fixups(relocs,self.symbol,self.machine)),
+			});
+		}
+		let bdd_start=4+overrides_size;
+		let bdd=try!(data.ref_at::<BddInfo>(FP::new(bdd_start as u32)));
+		let bdd_nodes=try!(data.ref_slice_at::<BddNode>(FP::new((bdd_start+size_of::<BddInfo>()) as u32),bdd.bdd_size/size_of::<BddNode>() as u32));
+		Ok(FunctionOverrides{overrides:overrides,bdd_version:bdd.version,bdd_nodes:bdd_nodes})
+	}
+}
@@ -15,14 +15,24 @@ extern crate lazy_static;
 
 #[macro_use]
 extern crate bitflags;
+extern crate md5;
 
 pub mod types;
 mod utility;
+pub mod ordinals;
+pub mod dependencies;
+pub mod apiset;
+pub mod rebase;
+pub mod dvrt;
+pub mod resources;
 
 use std::mem::{transmute,size_of};
+use std::ffi::{OsStr,OsString};
+use std::cell::Cell;
+use std::cmp::Ordering;
 
 use types::*;
-use utility::{RefSafe,URP,URPConvert,FPRef};
+use utility::{RefSafe,URP,URPConvert,FPRef,read_u32,push_u16,push_u32};
 pub use utility::{FP,RVA,CChar,Error,Result,AsOsStr};
 
 #[cfg(target_endian="big")] const E:ENDIANNESS_NOT_SUPPORTED=();
@@ -33,6 +43,7 @@ pub struct Pe<'data> {
 	oh: PeOptionalHeader<'data>,
 	directories: &'data [DataDirectory<u32>],
 	sections: &'data [SectionHeader],
+	mapped: bool,
 }
 
 #[derive(Copy,Clone)]
@@ -56,6 +67,13 @@ impl<'data> PeOptionalHeader<'data> {
 		}
 	}
 
+	pub fn get_image_base(&self) -> u64 {
+		match self {
+			&PeOptionalHeader::Pe32(h) => h.image_base as u64,
+			&PeOptionalHeader::Pe32Plus(h) => h.image_base,
+		}
+	}
+
 	pub fn get_check_sum(&self) -> &'data u32 {
 		match self {
 			&PeOptionalHeader::Pe32(h) => &h.check_sum,
@@ -64,6 +82,34 @@ impl<'data> PeOptionalHeader<'data> {
 	}
 }
 
+/// A copy of the load config directory. Fields that are not present in the
+/// image are zero.
+#[derive(Clone,Debug)]
+pub enum LoadConfig {
+	Pe32(LoadConfigDirectory32),
+	Pe32Plus(LoadConfigDirectory64),
+}
+
+impl LoadConfig {
+	/// The size of the directory in the image
+	pub fn get_size(&self) -> u32 {
+		match self {
+			&LoadConfig::Pe32(ref lc) => lc.size,
+			&LoadConfig::Pe32Plus(ref lc) => lc.size,
+		}
+	}
+
+	/// The offset of the dynamic value relocation table and the 1-based
+	/// index of the section it's in, if there is one
+	pub fn get_dynamic_value_reloc_table(&self) -> Option<(u32,u16)> {
+		let (offset,section)=match self {
+			&LoadConfig::Pe32(ref lc) => (lc.dynamic_value_reloc_table_offset,lc.dynamic_value_reloc_table_section),
+			&LoadConfig::Pe32Plus(ref lc) => (lc.dynamic_value_reloc_table_offset,lc.dynamic_value_reloc_table_section),
+		};
+		if section==0 { None } else { Some((offset,section)) }
+	}
+}
+
 pub trait Directory: RefSafe {
 	type Type: utility::Size4Bytes + URP<Self>;
 
@@ -85,28 +131,163 @@ macro_rules! directory_entry(
 
 directory_entry!(ExportTable         = RVA<ExportDirectory>);
 directory_entry!(BaseRelocationTable = RVA<RelocationBlock>);
+directory_entry!(ImportTable         = RVA<ImportDescriptor>);
+directory_entry!(DelayImportDescriptor = RVA<DelayImportDescriptor>);
+directory_entry!(BoundImport         = RVA<BoundImportDescriptor>);
+directory_entry!(ResourceTable       = RVA<ResourceDirectory>);
 
+#[derive(Clone)]
 pub struct Exports<'pe,'data: 'pe> {
 	pe: &'pe Pe<'data>,
 	ddir: &'data DataDirectory<RVA<ExportDirectory>>,
 	edir: &'data ExportDirectory,
+	names_sorted: Cell<Option<bool>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug,PartialEq,Eq)]
 pub enum ExportAddress<'data> {
 	Export(&'data RVA<Fn()>),
 	Forwarder(&'data RVA<[CChar]>),
 }
 
+/// A parsed forwarder string, such as `NTDLL.RtlAllocateHeap` or
+/// `api-ms-win-core-x.dll.#12`
+#[derive(Debug,Clone,Copy)]
+pub struct Forwarder<'data> {
+	/// The module name, which may or may not include an extension
+	pub module: &'data [CChar],
+	pub symbol: ForwarderSymbol<'data>,
+}
+
+#[derive(Debug,Clone,Copy)]
+pub enum ForwarderSymbol<'data> {
+	Name(&'data [CChar]),
+	Ordinal(u32),
+}
+
+pub struct ExportIter<'pe,'data: 'pe> {
+	exports: Exports<'pe,'data>,
+	addresses: &'data [RawExportAddress],
+	names: Vec<(u16,&'data [CChar])>,
+	next_offset: usize,
+	next_name: usize,
+}
+
+/// An entry in the export address table
+#[derive(Debug)]
+pub struct Export<'data> {
+	/// The ordinal, including `ordinal_base`
+	pub ordinal: u32,
+	/// The names this entry is exported under, if any
+	pub names: Vec<&'data [CChar]>,
+	pub address: ExportAddress<'data>,
+}
+
+pub struct ImportIter<'pe,'data: 'pe> {
+	pe: &'pe Pe<'data>,
+	next_desc: Option<RVA<ImportDescriptor>>,
+}
+
+pub struct Import<'pe,'data: 'pe> {
+	pe: &'pe Pe<'data>,
+	desc: &'data ImportDescriptor,
+	name: &'data [CChar],
+}
+
+#[derive(Debug,Clone,Copy)]
+pub enum ImportLookup<'data> {
+	Ordinal(u16),
+	HintName(u16,&'data [CChar]),
+}
+
+pub struct DelayImportIter<'pe,'data: 'pe> {
+	pe: &'pe Pe<'data>,
+	next_desc: Option<RVA<DelayImportDescriptor>>,
+}
+
+pub struct DelayImport<'pe,'data: 'pe> {
+	pe: &'pe Pe<'data>,
+	desc: &'data DelayImportDescriptor,
+	name: &'data [CChar],
+	bias: u32,
+}
+
+pub struct BoundImportIter<'pe,'data: 'pe> {
+	pe: &'pe Pe<'data>,
+	base: RVA<()>,
+	next_desc: Option<RVA<BoundImportDescriptor>>,
+}
+
+pub struct BoundImport<'pe,'data: 'pe> {
+	pe: &'pe Pe<'data>,
+	base: RVA<()>,
+	desc: &'data BoundImportDescriptor,
+	name: &'data [CChar],
+	forwarders: &'data [BoundForwarderRef],
+}
+
+/// Maps import address table slots back to the imported symbols
+pub struct ImportAddressMap<'data> {
+	slots: Vec<ImportSlot<'data>>,
+	slot_size: u32,
+}
+
+#[derive(Debug,Clone,Copy)]
+pub struct ImportSlot<'data> {
+	pub rva: RVA<()>,
+	pub dll: &'data [CChar],
+	pub import: ImportLookup<'data>,
+	pub delay_load: bool,
+}
+
+pub struct ThunkIter<'pe,'data: 'pe> {
+	pe: &'pe Pe<'data>,
+	next_thunk: Option<RVA<()>>,
+	// Subtracted from hint/name addresses, for VA-based tables
+	bias: u32,
+}
+
 pub struct RelocationIter<'pe,'data: 'pe> {
 	pe: &'pe Pe<'data>,
 	next_rblock: RVA<RelocationBlock>,
 	end: RVA<()>,
 }
 
+/// Builds a base relocation table
+#[derive(Debug,Clone,Default)]
+pub struct RelocationBuilder {
+	entries: Vec<RelocationEntry>,
+}
+
+pub struct RelocationEntryIter<'pe,'data: 'pe> {
+	blocks: Option<RelocationIter<'pe,'data>>,
+	machine: Machine,
+	page_rva: RVA<()>,
+	relocs: &'data [Relocation],
+}
+
+/// A single base relocation. `ABSOLUTE` padding entries are never returned.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct RelocationEntry {
+	pub rva: RVA<()>,
+	pub rtype: RelocationType,
+	/// For `HIGHADJ`, the low 16 bits of the adjusted value, which are stored
+	/// in the following entry
+	pub param: Option<u16>,
+}
+
 impl<'data> Pe<'data> {
 // PRIVATE
 	fn resolve_rva_raw<'a>(&self, rva: RVA<()>, length: u32, max_length: Option<&'a mut u32>) -> Result<FP<()>> {
+		if self.mapped {
+			if rva+(length as usize)>self.data.len() {
+				return Err(Error::ResolveMapError);
+			}
+			if let Some(max)=max_length {
+				*max=(self.data.len()-rva.get() as usize) as u32;
+			}
+			return Ok(FP::new(rva.get()));
+		}
 		for section in self.sections {
 			if section.virtual_address<=rva && rva<section.virtual_address+section.virtual_size {
 				if rva+length>section.virtual_address+section.size_of_raw_data {
@@ -118,6 +299,18 @@ impl<'data> Pe<'data> {
 				return Ok(section.pointer_to_raw_data.offset(rva.get()-section.virtual_address.get()));
 			}
 		}
+		// The headers are mapped at the start of the image, some directories
+		// (such as the bound import directory) are stored there.
+		let size_of_headers=*self.oh.get_size_of_headers();
+		if rva.get()<size_of_headers {
+			if rva.get()+length>size_of_headers {
+				return Err(Error::ResolveMapError);
+			}
+			if let Some(max)=max_length {
+				*max=size_of_headers-rva.get();
+			}
+			return Ok(FP::new(rva.get()));
+		}
 		Err(Error::ResolveMapError)
 	}
 
@@ -126,13 +319,26 @@ impl<'data> Pe<'data> {
 		Ok(try!(self.resolve_rva_raw(rva+0u32,length,None)).offset(0))
 	}
 
+	fn thunk_size(&self) -> u32 {
+		match self.oh {
+			PeOptionalHeader::Pe32(_) => size_of::<ThunkData32>() as u32,
+			PeOptionalHeader::Pe32Plus(_) => size_of::<ThunkData64>() as u32,
+		}
+	}
+
+	fn read_thunk(&self, rva: RVA<()>) -> Result<Option<Thunk>> {
+		Ok(match self.oh {
+			PeOptionalHeader::Pe32(_) => try!(self.ref_at::<ThunkData32>(rva.offset(0))).decode(),
+			PeOptionalHeader::Pe32Plus(_) => try!(self.ref_at::<ThunkData64>(rva.offset(0))).decode(),
+		})
+	}
+
 	fn resolve_rva_slice<T>(&self, rva: RVA<[T]>, count: u32) -> Result<FP<[T]>> {
 		let length=size_of::<T>() as u32*count;
 		Ok(try!(self.resolve_rva_raw(rva+0u32,length,None)).offset(0))
 	}
 
-// PUBLIC
-	pub fn new(data: &'data [u8]) -> Result<Pe<'data>> {
+	fn parse(data: &'data [u8], mapped: bool) -> Result<Pe<'data>> {
 		let sig=*try!(data.ref_at(FP::<u16>::new(0)));
 		let pe_header_fp=if sig==DOS_SIGNATURE {
 			try!(data.ref_at(FP::<DosHeader>::new(0))).new
@@ -178,7 +384,23 @@ impl<'data> Pe<'data> {
 		let pe_sec_fp=pe_dd_fp.offset(n*(size_of::<DataDirectory<u32>>() as u32));
 		let pe_sec=try!(data.ref_slice_at(pe_sec_fp,pe_header.number_of_sections as u32));
 
-		Ok(Pe{data:data,h:pe_header,oh:pe_oh,directories:pe_dd,sections:pe_sec})
+		Ok(Pe{data:data,h:pe_header,oh:pe_oh,directories:pe_dd,sections:pe_sec,mapped:mapped})
+	}
+
+// PUBLIC
+	/// Parse an image in file layout
+	pub fn new(data: &'data [u8]) -> Result<Pe<'data>> {
+		Pe::parse(data,false)
+	}
+
+	/// Parse an image in mapped layout, i.e. with sections at their virtual
+	/// addresses
+	pub fn new_mapped(data: &'data [u8]) -> Result<Pe<'data>> {
+		Pe::parse(data,true)
+	}
+
+	pub fn is_mapped(&self) -> bool {
+		self.mapped
 	}
 
 	pub fn ref_at<T: RefSafe>(&self, rva: RVA<T>) -> Result<&'data T> {
@@ -241,18 +463,131 @@ impl<'data> Pe<'data> {
 		Ok(unsafe{transmute::<&'data DataDirectory<_>,&'data DataDirectory<_>>(&self.directories[entry as usize])})
 	}
 
-	pub fn get_exports(&self) -> Result<Exports> {
+	pub fn get_exports<'pe>(&'pe self) -> Result<Exports<'pe,'data>> {
 		let ddir=try!(self.get_directory::<ExportDirectory>());
 		if (ddir.size as usize)<size_of::<ExportDirectory>() {
 			return Err(Error::InvalidSize);
 		}
-		Ok(Exports{pe:self,ddir:ddir,edir:try!(self.ref_at(ddir.virtual_address))})
+		Ok(Exports{pe:self,ddir:ddir,edir:try!(self.ref_at(ddir.virtual_address)),names_sorted:Cell::new(None)})
+	}
+
+	pub fn get_imports<'pe>(&'pe self) -> Result<ImportIter<'pe,'data>> {
+		let ddir=try!(self.get_directory::<ImportDescriptor>());
+		let next_desc=if ddir.virtual_address.get()==0 { None } else { Some(ddir.virtual_address) };
+		Ok(ImportIter{pe:self,next_desc:next_desc})
+	}
+
+	pub fn get_delay_imports<'pe>(&'pe self) -> Result<DelayImportIter<'pe,'data>> {
+		let ddir=try!(self.get_directory::<DelayImportDescriptor>());
+		let next_desc=if ddir.virtual_address.get()==0 { None } else { Some(ddir.virtual_address) };
+		Ok(DelayImportIter{pe:self,next_desc:next_desc})
+	}
+
+	pub fn get_bound_imports<'pe>(&'pe self) -> Result<BoundImportIter<'pe,'data>> {
+		let ddir=try!(self.get_directory::<BoundImportDescriptor>());
+		let next_desc=if ddir.virtual_address.get()==0 { None } else { Some(ddir.virtual_address) };
+		Ok(BoundImportIter{pe:self,base:ddir.virtual_address+0u32,next_desc:next_desc})
+	}
+
+	/// Includes the slots of both regular and delay-loaded imports
+	pub fn get_import_address_map(&self) -> Result<ImportAddressMap<'data>> {
+		let slot_size=self.thunk_size();
+		let mut slots=vec![];
+		for import in try!(self.get_imports()) {
+			let import=try!(import);
+			let iat=import.get_descriptor().import_address_table;
+			for (i,lookup) in import.get_lookup_table().enumerate() {
+				slots.push(ImportSlot{rva:iat.offset(i as u32*slot_size),dll:import.get_name(),import:try!(lookup),delay_load:false});
+			}
+		}
+		match self.get_delay_imports() {
+			Ok(delay_imports) => for import in delay_imports {
+				let import=try!(import);
+				let iat=import.get_address_table();
+				for (i,lookup) in import.get_lookup_table().enumerate() {
+					slots.push(ImportSlot{rva:iat.offset(i as u32*slot_size),dll:import.get_name(),import:try!(lookup),delay_load:true});
+				}
+			},
+			Err(Error::DirectoryMissing) => {},
+			Err(e) => return Err(e),
+		}
+		slots.sort_by(|a,b|a.rva.cmp(&b.rva));
+		Ok(ImportAddressMap{slots:slots,slot_size:slot_size})
+	}
+
+	/// Compute the import hash, as defined by pefile. Imports by ordinal from
+	/// some well-known DLLs are mapped to their names. Returns an empty string
+	/// if the image has no imports.
+	pub fn imphash(&self) -> Result<String> {
+		let mut has_imports=false;
+		let mut functions=vec![];
+		for import in try!(self.get_imports()) {
+			let import=try!(import);
+			has_imports=true;
+			let dll=import.get_name().as_os_str().to_string_lossy().to_ascii_lowercase();
+			let module=match dll.rfind('.') {
+				Some(pos) if ["ocx","sys","dll"].contains(&&dll[pos+1..]) => &dll[..pos],
+				_ => &dll[..],
+			};
+			for lookup in import.get_lookup_table() {
+				let function=match try!(lookup) {
+					ImportLookup::HintName(_,name) => name.as_os_str().to_string_lossy().to_ascii_lowercase(),
+					ImportLookup::Ordinal(ordinal) => match ordinals::lookup_pefile(&dll,ordinal) {
+						Some(name) => name.to_ascii_lowercase(),
+						None => format!("ord{}",ordinal),
+					},
+				};
+				if !function.is_empty() {
+					functions.push(format!("{}.{}",module,function));
+				}
+			}
+		}
+		if !has_imports {
+			return Ok(String::new());
+		}
+		Ok(format!("{:x}",md5::compute(functions.join(",").as_bytes())))
 	}
 
 	pub fn get_relocations<'pe>(&'pe self) -> Result<RelocationIter<'pe,'data>> {
 		let ddir=try!(self.get_directory::<RelocationBlock>());
 		Ok(RelocationIter{pe:self,next_rblock:ddir.virtual_address,end:ddir.virtual_address+ddir.size})
 	}
+
+	pub fn get_load_config(&self) -> Result<LoadConfig> {
+		fn read<T: RefSafe+Default>(bytes: &[u8]) -> T {
+			let mut lc=T::default();
+			let len=::std::cmp::min(bytes.len(),size_of::<T>());
+			unsafe{::std::ptr::copy_nonoverlapping(bytes.as_ptr(),&mut lc as *mut T as *mut u8,len)};
+			lc
+		}
+
+		let ddir=try!(self.get_directory_raw(DirectoryEntry::LoadConfigTable));
+		if ddir.virtual_address.get()==0 {
+			return Err(Error::DirectoryMissing);
+		}
+		let size=try!(read_u32(try!(self.ref_slice_at(ddir.virtual_address,4)),0));
+		let bytes=try!(self.ref_slice_at(ddir.virtual_address,size));
+		Ok(match self.oh {
+			PeOptionalHeader::Pe32(_) => LoadConfig::Pe32(read(bytes)),
+			PeOptionalHeader::Pe32Plus(_) => LoadConfig::Pe32Plus(read(bytes)),
+		})
+	}
+
+	/// Returns `DirectoryMissing` if the load config doesn't reference a
+	/// dynamic value relocation table
+	pub fn get_dynamic_relocations(&self) -> Result<dvrt::DynamicRelocationIter<'data>> {
+		let (offset,section)=try!(try!(self.get_load_config()).get_dynamic_value_reloc_table().ok_or(Error::DirectoryMissing));
+		let section=try!(self.sections.get(section as usize-1).ok_or(Error::ResolveMapError));
+		let rva: RVA<DynamicRelocationTable>=RVA::new(try!(section.virtual_address.get().checked_add(offset).ok_or(Error::InvalidSize)));
+		let size=try!(self.ref_at(rva)).size;
+		let size=try!((size_of::<DynamicRelocationTable>() as u32).checked_add(size).ok_or(Error::InvalidSize));
+		let data=try!(self.ref_slice_at(rva.offset(0),size));
+		let is64=match self.oh {
+			PeOptionalHeader::Pe32(_) => false,
+			PeOptionalHeader::Pe32Plus(_) => true,
+		};
+		dvrt::DynamicRelocationIter::new(data,is64,self.h.machine)
+	}
 }
 
 impl<'pe,'data: 'pe> Exports<'pe, 'data> {
@@ -285,23 +620,412 @@ impl<'pe,'data: 'pe> Exports<'pe, 'data> {
 		self.pe.ref_slice_at(self.edir.export_address_table,self.edir.address_table_entries)
 	}
 
+	/// Whether the name pointer table is sorted, as required by the spec. This
+	/// is checked once and then cached.
+	fn are_names_sorted(&self) -> bool {
+		if let Some(sorted)=self.names_sorted.get() {
+			return sorted;
+		}
+		let sorted=self.get_names().ok().map_or(false,|names|{
+			let mut prev: Option<&OsStr>=None;
+			for &name_rva in names {
+				let name=match self.pe.ref_cstr_at(name_rva) {
+					Ok(cstr) => cstr.as_os_str(),
+					Err(_) => return false,
+				};
+				if prev.map_or(false,|prev|prev>name) {
+					return false;
+				}
+				prev=Some(name);
+			}
+			true
+		});
+		self.names_sorted.set(Some(sorted));
+		sorted
+	}
+
+	/// Uses a binary search if the name pointer table is sorted, and a linear
+	/// search otherwise.
 	pub fn lookup_symbol(&self, symbol: &str) -> Result<ExportAddress<'data>> {
-		let pos=try!(try!(self.get_names()).iter().position(|&name_rva|{
-			self.pe.ref_cstr_at(name_rva).ok().map_or(false,|cstr|cstr.as_os_str()==symbol)
-		}).ok_or(Error::SymbolNotFound));
+		let names=try!(self.get_names());
+		let symbol=OsStr::new(symbol);
+		let pos=if self.are_names_sorted() {
+			names.binary_search_by(|&name_rva|{
+				self.pe.ref_cstr_at(name_rva).map(|cstr|cstr.as_os_str().cmp(symbol)).unwrap_or(Ordering::Less)
+			}).ok()
+		} else {
+			names.iter().position(|&name_rva|{
+				self.pe.ref_cstr_at(name_rva).ok().map_or(false,|cstr|cstr.as_os_str()==symbol)
+			})
+		};
+		let pos=try!(pos.ok_or(Error::SymbolNotFound));
 		let ordinal_offset=try!(self.get_ordinal_offsets())[pos];
 		let export=try!(try!(self.get_export_addresses()).get(ordinal_offset as usize).ok_or(Error::ExportNotFound));
 		Ok(self.concretize_export_address(export))
 	}
+
+	/// Look up an export by ordinal number, i.e. including `ordinal_base`.
+	pub fn lookup_ordinal(&self, ordinal: u32) -> Result<ExportAddress<'data>> {
+		let offset=try!(ordinal.checked_sub(self.edir.ordinal_base).ok_or(Error::SymbolNotFound));
+		let export=try!(try!(self.get_export_addresses()).get(offset as usize).ok_or(Error::SymbolNotFound));
+		if export.0.get()==0 {
+			return Err(Error::SymbolNotFound);
+		}
+		Ok(self.concretize_export_address(export))
+	}
+
+	pub fn parse_forwarder(&self, forwarder: &RVA<[CChar]>) -> Result<Forwarder<'data>> {
+		Forwarder::parse(try!(self.pe.ref_cstr_at(*forwarder)))
+	}
+
+	/// Iterate over the export address table, skipping empty entries.
+	pub fn iter(&self) -> Result<ExportIter<'pe,'data>> {
+		let mut names=vec![];
+		for (&name_rva,&offset) in try!(self.get_names()).iter().zip(try!(self.get_ordinal_offsets())) {
+			names.push((offset,try!(self.pe.ref_cstr_at(name_rva))));
+		}
+		// stable sort, so that names for the same entry stay in table order
+		names.sort_by_key(|&(offset,_)|offset);
+		Ok(ExportIter{exports:self.clone(),addresses:try!(self.get_export_addresses()),names:names,next_offset:0,next_name:0})
+	}
+}
+
+impl<'data> Forwarder<'data> {
+	/// Split a forwarder string at the last dot. A trailing NULL terminator is
+	/// ignored.
+	pub fn parse(forwarder: &'data [CChar]) -> Result<Forwarder<'data>> {
+		let forwarder=match forwarder.iter().position(|c|c.get()==0) {
+			Some(len) => &forwarder[..len],
+			None => forwarder,
+		};
+		let dot=try!(forwarder.iter().rposition(|c|c.get()==b'.').ok_or(Error::InvalidForwarder));
+		let (module,symbol)=(&forwarder[..dot],&forwarder[dot+1..]);
+		if module.is_empty() || symbol.is_empty() {
+			return Err(Error::InvalidForwarder);
+		}
+		let symbol=if symbol[0].get()==b'#' {
+			let ordinal=try!(symbol[1..].as_os_str().to_str().and_then(|s|s.parse().ok()).ok_or(Error::InvalidForwarder));
+			ForwarderSymbol::Ordinal(ordinal)
+		} else {
+			ForwarderSymbol::Name(symbol)
+		};
+		Ok(Forwarder{module:module,symbol:symbol})
+	}
+
+	/// The file name of the target module. The loader appends `.dll` if the
+	/// module name has no extension.
+	pub fn get_dll_name(&self) -> OsString {
+		let mut name=self.module.as_os_str().to_owned();
+		if !self.module.iter().any(|c|c.get()==b'.') {
+			name.push(".dll");
+		}
+		name
+	}
+}
+
+impl<'pe,'data: 'pe> Iterator for ExportIter<'pe,'data> {
+	type Item=Export<'data>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		while let Some(addr)=self.addresses.get(self.next_offset) {
+			let offset=self.next_offset;
+			self.next_offset+=1;
+			let mut names=vec![];
+			while let Some(&(name_offset,name))=self.names.get(self.next_name) {
+				if name_offset as usize>offset {
+					break;
+				}
+				if name_offset as usize==offset {
+					names.push(name);
+				}
+				self.next_name+=1;
+			}
+			if addr.0.get()==0 {
+				continue;
+			}
+			return Some(Export{
+				ordinal:self.exports.edir.ordinal_base.wrapping_add(offset as u32),
+				names:names,
+				address:self.exports.concretize_export_address(addr),
+			});
+		}
+		None
+	}
+}
+
+impl<'pe,'data: 'pe> ImportIter<'pe,'data> {
+	fn advance(&mut self, desc_rva: RVA<ImportDescriptor>) -> Result<Option<Import<'pe,'data>>> {
+		let desc=try!(self.pe.ref_at(desc_rva));
+		if desc.is_null() {
+			return Ok(None);
+		}
+		let name=try!(self.pe.ref_cstr_at(desc.name));
+		self.next_desc=Some(desc_rva.offset(size_of::<ImportDescriptor>() as u32));
+		Ok(Some(Import{pe:self.pe,desc:desc,name:name}))
+	}
+}
+
+impl<'pe,'data: 'pe> Iterator for ImportIter<'pe,'data> {
+	type Item=Result<Import<'pe,'data>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		// Stop after the NULL descriptor or after the first error
+		let desc_rva=match self.next_desc.take() {
+			Some(rva) => rva,
+			None => return None,
+		};
+		match self.advance(desc_rva) {
+			Ok(Some(import)) => Some(Ok(import)),
+			Ok(None) => None,
+			Err(e) => Some(Err(e)),
+		}
+	}
+}
+
+impl<'pe,'data: 'pe> Import<'pe,'data> {
+	pub fn get_descriptor(&self) -> &'data ImportDescriptor {
+		self.desc
+	}
+
+	pub fn get_name(&self) -> &'data [CChar] {
+		self.name
+	}
+
+	/// Iterates over the import lookup table. Some linkers don't emit a
+	/// lookup table, in which case the (unbound) import address table is used.
+	pub fn get_lookup_table(&self) -> ThunkIter<'pe,'data> {
+		let table=if self.desc.import_lookup_table.get()!=0 {
+			self.desc.import_lookup_table
+		} else {
+			self.desc.import_address_table
+		};
+		ThunkIter{pe:self.pe,next_thunk:Some(table),bias:0}
+	}
+}
+
+impl<'pe,'data: 'pe> DelayImportIter<'pe,'data> {
+	fn advance(&mut self, desc_rva: RVA<DelayImportDescriptor>) -> Result<Option<DelayImport<'pe,'data>>> {
+		let desc=try!(self.pe.ref_at(desc_rva));
+		if desc.is_null() {
+			return Ok(None);
+		}
+		// Only 32-bit images have VA-based descriptors
+		let bias=if desc.is_rva_based() { 0 } else { self.pe.oh.get_image_base() as u32 };
+		let name=try!(self.pe.ref_cstr_at(RVA::new(desc.name.get().wrapping_sub(bias))));
+		self.next_desc=Some(desc_rva.offset(size_of::<DelayImportDescriptor>() as u32));
+		Ok(Some(DelayImport{pe:self.pe,desc:desc,name:name,bias:bias}))
+	}
+}
+
+impl<'pe,'data: 'pe> Iterator for DelayImportIter<'pe,'data> {
+	type Item=Result<DelayImport<'pe,'data>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		// Stop after the NULL descriptor or after the first error
+		let desc_rva=match self.next_desc.take() {
+			Some(rva) => rva,
+			None => return None,
+		};
+		match self.advance(desc_rva) {
+			Ok(Some(import)) => Some(Ok(import)),
+			Ok(None) => None,
+			Err(e) => Some(Err(e)),
+		}
+	}
+}
+
+impl<'pe,'data: 'pe> DelayImport<'pe,'data> {
+	fn to_rva<T: ?Sized>(&self, addr: RVA<T>) -> RVA<T> {
+		if addr.get()==0 {
+			addr
+		} else {
+			RVA::new(addr.get().wrapping_sub(self.bias))
+		}
+	}
+
+	pub fn get_descriptor(&self) -> &'data DelayImportDescriptor {
+		self.desc
+	}
+
+	pub fn get_name(&self) -> &'data [CChar] {
+		self.name
+	}
+
+	/// The following functions return RVAs for both RVA-based and VA-based
+	/// descriptors. A zero RVA indicates the table is not present.
+	pub fn get_module_handle(&self) -> RVA<()> {
+		self.to_rva(self.desc.module_handle)
+	}
+
+	pub fn get_address_table(&self) -> RVA<()> {
+		self.to_rva(self.desc.delay_import_address_table)
+	}
+
+	pub fn get_name_table(&self) -> RVA<()> {
+		self.to_rva(self.desc.delay_import_name_table)
+	}
+
+	pub fn get_bound_address_table(&self) -> RVA<()> {
+		self.to_rva(self.desc.bound_delay_import_table)
+	}
+
+	pub fn get_unload_address_table(&self) -> RVA<()> {
+		self.to_rva(self.desc.unload_delay_import_table)
+	}
+
+	/// Iterates over the delay import name table. Nothing is returned if
+	/// there is no name table.
+	pub fn get_lookup_table(&self) -> ThunkIter<'pe,'data> {
+		let table=self.get_name_table();
+		ThunkIter{pe:self.pe,next_thunk:if table.get()==0 { None } else { Some(table) },bias:self.bias}
+	}
+}
+
+impl<'pe,'data: 'pe> BoundImportIter<'pe,'data> {
+	fn advance(&mut self, desc_rva: RVA<BoundImportDescriptor>) -> Result<Option<BoundImport<'pe,'data>>> {
+		let desc=try!(self.pe.ref_at(desc_rva));
+		if desc.is_null() {
+			return Ok(None);
+		}
+		let name=try!(self.pe.ref_cstr_at(self.base.offset(desc.offset_module_name as u32)));
+		let forwarders_rva=desc_rva.offset(size_of::<BoundImportDescriptor>() as u32);
+		let forwarders=try!(self.pe.ref_slice_at(forwarders_rva,desc.number_of_module_forwarder_refs as u32));
+		// Forwarder references are stored inline, following their descriptor
+		self.next_desc=Some(forwarders_rva.offset((forwarders.len()*size_of::<BoundForwarderRef>()) as u32));
+		Ok(Some(BoundImport{pe:self.pe,base:self.base,desc:desc,name:name,forwarders:forwarders}))
+	}
+}
+
+impl<'pe,'data: 'pe> Iterator for BoundImportIter<'pe,'data> {
+	type Item=Result<BoundImport<'pe,'data>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		// Stop after the NULL descriptor or after the first error
+		let desc_rva=match self.next_desc.take() {
+			Some(rva) => rva,
+			None => return None,
+		};
+		match self.advance(desc_rva) {
+			Ok(Some(import)) => Some(Ok(import)),
+			Ok(None) => None,
+			Err(e) => Some(Err(e)),
+		}
+	}
+}
+
+impl<'pe,'data: 'pe> BoundImport<'pe,'data> {
+	pub fn get_descriptor(&self) -> &'data BoundImportDescriptor {
+		self.desc
+	}
+
+	pub fn get_name(&self) -> &'data [CChar] {
+		self.name
+	}
+
+	pub fn get_forwarders(&self) -> &'data [BoundForwarderRef] {
+		self.forwarders
+	}
+
+	pub fn get_forwarder_name(&self, forwarder: &BoundForwarderRef) -> Result<&'data [CChar]> {
+		self.pe.ref_cstr_at(self.base.offset(forwarder.offset_module_name as u32))
+	}
+
+	/// Whether the binding is still valid for this version of the imported
+	/// module. Bound addresses for stale bindings are ignored by the loader.
+	pub fn is_bound_to(&self, module: &Pe) -> bool {
+		self.desc.time_date_stamp==module.get_header().time_date_stamp
+	}
+}
+
+impl<'data> ImportLookup<'data> {
+	/// Get the name of the imported symbol. Imports by ordinal from some
+	/// well-known DLLs are looked up in the tables in the `ordinals` module.
+	pub fn get_symbol_name(&self, dll: &[CChar]) -> Option<&'data OsStr> {
+		match *self {
+			ImportLookup::HintName(_,name) => Some(name.as_os_str()),
+			ImportLookup::Ordinal(ordinal) => ordinals::lookup(&dll.as_os_str().to_string_lossy(),ordinal).map(OsStr::new),
+		}
+	}
+
+	/// Like `get_symbol_name`, but falls back to `#<ordinal>` for unknown ordinals
+	pub fn get_display_name(&self, dll: &[CChar]) -> String {
+		match (self.get_symbol_name(dll),*self) {
+			(Some(name),_) => name.to_string_lossy().into_owned(),
+			(None,ImportLookup::Ordinal(ordinal)) => format!("#{}",ordinal),
+			(None,ImportLookup::HintName(..)) => unreachable!(),
+		}
+	}
+}
+
+impl<'data> ImportAddressMap<'data> {
+	/// Returns the slots sorted by RVA
+	pub fn get_slots(&self) -> &[ImportSlot<'data>] {
+		&self.slots
+	}
+
+	/// Find the slot containing `rva`
+	pub fn lookup(&self, rva: RVA<()>) -> Option<&ImportSlot<'data>> {
+		match self.slots.binary_search_by(|slot|slot.rva.cmp(&rva)) {
+			Ok(i) => Some(&self.slots[i]),
+			Err(0) => None,
+			Err(i) => {
+				let slot=&self.slots[i-1];
+				if rva.get()<slot.rva.get()+self.slot_size { Some(slot) } else { None }
+			},
+		}
+	}
+}
+
+impl<'pe,'data: 'pe> ThunkIter<'pe,'data> {
+	fn advance(&mut self, thunk_rva: RVA<()>) -> Result<Option<ImportLookup<'data>>> {
+		let lookup=match try!(self.pe.read_thunk(thunk_rva)) {
+			None => return Ok(None),
+			Some(Thunk::Ordinal(ordinal)) => ImportLookup::Ordinal(ordinal),
+			Some(Thunk::HintName(rva)) => {
+				let rva: RVA<u16>=RVA::new(rva.get().wrapping_sub(self.bias));
+				let hint=*try!(self.pe.ref_at(rva));
+				ImportLookup::HintName(hint,try!(self.pe.ref_cstr_at(rva.offset(size_of::<u16>() as u32))))
+			},
+		};
+		self.next_thunk=Some(thunk_rva.offset(self.pe.thunk_size()));
+		Ok(Some(lookup))
+	}
+}
+
+impl<'pe,'data: 'pe> Iterator for ThunkIter<'pe,'data> {
+	type Item=Result<ImportLookup<'data>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		// Stop after the NULL entry or after the first error
+		let thunk_rva=match self.next_thunk.take() {
+			Some(rva) => rva,
+			None => return None,
+		};
+		match self.advance(thunk_rva) {
+			Ok(Some(lookup)) => Some(Ok(lookup)),
+			Ok(None) => None,
+			Err(e) => Some(Err(e)),
+		}
+	}
 }
 
 impl<'pe,'data: 'pe> RelocationIter<'pe,'data> {
 	fn advance(&mut self) -> Result<(RVA<()>,&'data [Relocation])> {
 		let rblock=try!(self.pe.ref_at(self.next_rblock));
-		let relocs: &[Relocation]=try!(self.pe.ref_slice_at(self.next_rblock.offset(size_of::<RelocationBlock>() as u32),rblock.block_size/2));
-		self.next_rblock=self.next_rblock.offset(rblock.block_size);
+		let block_size=rblock.block_size;
+		if (block_size as usize)<size_of::<RelocationBlock>() {
+			return Err(Error::InvalidSize);
+		}
+		let count=(block_size-size_of::<RelocationBlock>() as u32)/2;
+		let relocs: &[Relocation]=try!(self.pe.ref_slice_at(self.next_rblock.offset(size_of::<RelocationBlock>() as u32),count));
+		self.next_rblock=self.next_rblock.offset(block_size);
 		Ok((rblock.page_rva,relocs))
 	}
+
+	/// Iterate over the individual relocations in all blocks
+	pub fn entries(self) -> RelocationEntryIter<'pe,'data> {
+		RelocationEntryIter{machine:self.pe.h.machine,blocks:Some(self),page_rva:RVA::new(0),relocs:&[]}
+	}
 }
 
 impl<'pe,'data: 'pe> Iterator for RelocationIter<'pe,'data> {
@@ -318,5 +1042,102 @@ impl<'pe,'data: 'pe> Iterator for RelocationIter<'pe,'data> {
 	}
 }
 
+impl RelocationBuilder {
+	pub fn new() -> RelocationBuilder {
+		RelocationBuilder{entries:vec![]}
+	}
+
+	/// Add a relocation. For `HIGHADJ`, use `add_highadj` to specify the
+	/// parameter, otherwise it is 0.
+	pub fn add(&mut self, rva: RVA<()>, rtype: RelocationType) {
+		self.entries.push(RelocationEntry{rva:rva,rtype:rtype,param:None});
+	}
+
+	pub fn add_highadj(&mut self, rva: RVA<()>, param: u16) {
+		self.entries.push(RelocationEntry{rva:rva,rtype:RelocationType::HIGHADJ,param:Some(param)});
+	}
+
+	pub fn add_entry(&mut self, entry: RelocationEntry) {
+		self.entries.push(entry);
+	}
+
+	/// Serialize the relocations, sorted by RVA and grouped into one block per
+	/// 4K page. Blocks are padded to a multiple of 4 bytes with `ABSOLUTE`
+	/// entries.
+	pub fn build(&self) -> Vec<u8> {
+		let mut entries=self.entries.clone();
+		entries.sort_by_key(|entry|entry.rva);
+		let mut out=vec![];
+		let mut start=0;
+		while start<entries.len() {
+			let page=entries[start].rva.get()&!0xfff;
+			let end=entries[start..].iter().position(|entry|entry.rva.get()&!0xfff!=page).map_or(entries.len(),|len|start+len);
+			let block=&entries[start..end];
+			start=end;
+			let mut relocs=vec![];
+			for entry in block {
+				relocs.push(Relocation::encode(entry.rtype,(entry.rva.get()-page) as u16));
+				if entry.rtype==RelocationType::HIGHADJ {
+					relocs.push(Relocation(entry.param.unwrap_or(0)));
+				}
+			}
+			if relocs.len()%2==1 {
+				relocs.push(Relocation::encode(RelocationType::ABSOLUTE,0));
+			}
+			push_u32(&mut out,page);
+			push_u32(&mut out,(size_of::<RelocationBlock>()+relocs.len()*size_of::<Relocation>()) as u32);
+			for reloc in relocs {
+				push_u16(&mut out,reloc.0);
+			}
+		}
+		out
+	}
+}
+
+impl<'pe,'data: 'pe> RelocationEntryIter<'pe,'data> {
+	fn advance(&mut self) -> Result<Option<RelocationEntry>> {
+		while self.relocs.is_empty() {
+			match self.blocks.as_mut().and_then(Iterator::next) {
+				Some(block) => {
+					let (page_rva,relocs)=try!(block);
+					self.page_rva=page_rva;
+					self.relocs=relocs;
+				},
+				None => return Ok(None),
+			}
+		}
+		let (rtype,offset)=self.relocs[0].decode(self.machine);
+		let mut consumed=1;
+		let param=match rtype {
+			RelocationType::HIGHADJ => {
+				consumed=2;
+				Some(try!(self.relocs.get(1).ok_or(Error::InvalidSize)).0)
+			},
+			_ => None,
+		};
+		self.relocs=&self.relocs[consumed..];
+		Ok(Some(RelocationEntry{rva:self.page_rva.offset(offset as u32),rtype:rtype,param:param}))
+	}
+}
+
+impl<'pe,'data: 'pe> Iterator for RelocationEntryIter<'pe,'data> {
+	type Item=Result<RelocationEntry>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			match self.advance() {
+				Ok(Some(RelocationEntry{rtype:RelocationType::ABSOLUTE,..})) => continue,
+				Ok(Some(entry)) => return Some(Ok(entry)),
+				Ok(None) => return None,
+				Err(e) => {
+					self.blocks=None;
+					self.relocs=&[];
+					return Some(Err(e))
+				},
+			}
+		}
+	}
+}
+
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,174 @@
+/*
+ * Zero-copy Portable Executable parser
+ *
+ * (C) Copyright 2016 Jethro G. Beekman
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; version 2
+ * of the License.
+ */
+
+//! Applying base relocations to move an image to a different base address.
+
+use super::{Pe,PeOptionalHeader,Error,Result};
+use super::types::{RelocationType,RelocationBlock};
+use super::types::image_characteristics::RELOCS_STRIPPED;
+use super::utility::{read_u16,read_u32,read_u64,write_u16,write_u32,write_u64};
+
+/// Offset of `image_base` in `PeOptionalHeader32` and `PeOptionalHeader64`
+const IMAGE_BASE_OFFSET_PE32: usize = 28;
+const IMAGE_BASE_OFFSET_PE32_PLUS: usize = 24;
+
+/// Position of the image base field in the image
+enum ImageBase {
+	Pe32(usize),
+	Pe32Plus(usize),
+}
+
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Layout {
+	/// As stored on disk
+	File,
+	/// As loaded in memory, with sections at their virtual addresses
+	Mapped,
+}
+
+/// Get the immediate of an ARM MOVW/MOVT instruction (A1/A2 encoding)
+fn arm_imm16(insn: u32) -> u32 {
+	((insn>>4)&0xf000) | (insn&0xfff)
+}
+
+fn arm_set_imm16(insn: u32, imm: u32) -> u32 {
+	(insn&!0xf0fff) | ((imm&0xf000)<<4) | (imm&0xfff)
+}
+
+/// Get the immediate of a Thumb-2 MOVW/MOVT instruction (T3 encoding), given
+/// as its two halfwords
+fn thumb_imm16(hw1: u16, hw2: u16) -> u32 {
+	let (hw1,hw2)=(hw1 as u32,hw2 as u32);
+	((hw1&0xf)<<12) | ((hw1&0x400)<<1) | ((hw2&0x7000)>>4) | (hw2&0xff)
+}
+
+fn thumb_set_imm16(hw1: u16, hw2: u16, imm: u32) -> (u16,u16) {
+	let hw1=(hw1&!0x40f) | ((imm>>12)&0xf) as u16 | (((imm>>11)&1)<<10) as u16;
+	let hw2=(hw2&!0x70ff) | (((imm>>8)&7)<<12) as u16 | (imm&0xff) as u16;
+	(hw1,hw2)
+}
+
+/// The number of bytes modified by a relocation of this type
+fn relocation_size(rtype: RelocationType) -> Result<u32> {
+	match rtype {
+		RelocationType::HIGH | RelocationType::LOW | RelocationType::HIGHADJ => Ok(2),
+		RelocationType::HIGHLOW => Ok(4),
+		RelocationType::DIR64 | RelocationType::ARM_MOV32A | RelocationType::ARM_MOV32T => Ok(8),
+		_ => Err(Error::UnsupportedRelocation),
+	}
+}
+
+fn apply(image: &mut [u8], pos: usize, rtype: RelocationType, param: Option<u16>, delta: u64) -> Result<()> {
+	let delta32=delta as u32;
+	match rtype {
+		RelocationType::HIGH => {
+			let value=((try!(read_u16(image,pos)) as u32)<<16).wrapping_add(delta32);
+			write_u16(image,pos,(value>>16) as u16);
+		},
+		RelocationType::LOW => {
+			let value=(try!(read_u16(image,pos)) as u32).wrapping_add(delta32);
+			write_u16(image,pos,value as u16);
+		},
+		RelocationType::HIGHADJ => {
+			let value=((try!(read_u16(image,pos)) as u32)<<16)
+				.wrapping_add(param.unwrap_or(0) as i16 as u32)
+				.wrapping_add(delta32)
+				.wrapping_add(0x8000);
+			write_u16(image,pos,(value>>16) as u16);
+		},
+		RelocationType::HIGHLOW => {
+			let value=try!(read_u32(image,pos)).wrapping_add(delta32);
+			write_u32(image,pos,value);
+		},
+		RelocationType::DIR64 => {
+			let value=try!(read_u64(image,pos)).wrapping_add(delta);
+			write_u64(image,pos,value);
+		},
+		RelocationType::ARM_MOV32A => {
+			let (movw,movt)=(try!(read_u32(image,pos)),try!(read_u32(image,pos+4)));
+			let value=(arm_imm16(movw) | (arm_imm16(movt)<<16)).wrapping_add(delta32);
+			write_u32(image,pos,arm_set_imm16(movw,value&0xffff));
+			write_u32(image,pos+4,arm_set_imm16(movt,value>>16));
+		},
+		RelocationType::ARM_MOV32T => {
+			let (movw1,movw2,movt1,movt2)=(try!(read_u16(image,pos)),try!(read_u16(image,pos+2)),try!(read_u16(image,pos+4)),try!(read_u16(image,pos+6)));
+			let value=(thumb_imm16(movw1,movw2) | (thumb_imm16(movt1,movt2)<<16)).wrapping_add(delta32);
+			let (movw1,movw2)=thumb_set_imm16(movw1,movw2,value&0xffff);
+			let (movt1,movt2)=thumb_set_imm16(movt1,movt2,value>>16);
+			write_u16(image,pos,movw1);
+			write_u16(image,pos+2,movw2);
+			write_u16(image,pos+4,movt1);
+			write_u16(image,pos+6,movt2);
+		},
+		_ => unreachable!(),
+	}
+	Ok(())
+}
+
+/// Apply all base relocations in `image` to move it to `new_base`. If
+/// `update_header` is set, the image base in the optional header is changed
+/// as well, otherwise the image can't be rebased again.
+///
+/// Returns `NotRelocatable` if the image has no base relocations, unless the
+/// image base doesn't change, and `InvalidImageBase` if `new_base` doesn't
+/// fit in a PE32 image. The image is not modified if any relocation can't be
+/// applied.
+pub fn rebase(image: &mut [u8], layout: Layout, new_base: u64, update_header: bool) -> Result<()> {
+	let (fixups,delta,image_base)={
+		let pe=try!(match layout {
+			Layout::File => Pe::new(image),
+			Layout::Mapped => Pe::new_mapped(image),
+		});
+		let delta=new_base.wrapping_sub(pe.get_optional_header().get_image_base());
+		let offset_of=|r: *const u8| r as usize-image.as_ptr() as usize;
+		let image_base=match pe.get_optional_header() {
+			PeOptionalHeader::Pe32(oh) => {
+				if new_base>u32::max_value() as u64 {
+					return Err(Error::InvalidImageBase);
+				}
+				ImageBase::Pe32(offset_of(oh as *const _ as *const u8)+IMAGE_BASE_OFFSET_PE32)
+			},
+			PeOptionalHeader::Pe32Plus(oh) => ImageBase::Pe32Plus(offset_of(oh as *const _ as *const u8)+IMAGE_BASE_OFFSET_PE32_PLUS),
+		};
+		if delta!=0 {
+			let has_relocations=pe.get_directory::<RelocationBlock>().map(|ddir|ddir.virtual_address.get()!=0 && ddir.size!=0).unwrap_or(false);
+			if !has_relocations || pe.get_header().characteristics.contains(RELOCS_STRIPPED) {
+				return Err(Error::NotRelocatable);
+			}
+		}
+		let mut fixups=vec![];
+		match pe.get_relocations() {
+			Ok(relocs) => for entry in relocs.entries() {
+				let entry=try!(entry);
+				let size=try!(relocation_size(entry.rtype));
+				let pos=try!(pe.resolve_rva_raw(entry.rva,size,None)).get() as usize;
+				if pos+size as usize>image.len() {
+					return Err(Error::ResolveMapError);
+				}
+				fixups.push((pos,entry.rtype,entry.param));
+			},
+			Err(Error::DirectoryMissing) => {},
+			Err(e) => return Err(e),
+		}
+		(fixups,delta,image_base)
+	};
+
+	for (pos,rtype,param) in fixups {
+		try!(apply(image,pos,rtype,param,delta));
+	}
+	if update_header {
+		match image_base {
+			ImageBase::Pe32(pos) => write_u32(image,pos,new_base as u32),
+			ImageBase::Pe32Plus(pos) => write_u64(image,pos,new_base),
+		}
+	}
+	Ok(())
+}
@@ -0,0 +1,373 @@
+/*
+ * Zero-copy Portable Executable parser
+ *
+ * (C) Copyright 2016 Jethro G. Beekman
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; version 2
+ * of the License.
+ */
+
+//! Serializing resource trees into `.rsrc` sections, and replacing the
+//! resource section of an image.
+
+use std::borrow::Cow;
+use std::cmp::{min,max};
+use std::mem::size_of;
+
+use super::{ResourceId,Resources};
+use super::super::{Pe,PeOptionalHeader,Error,Result};
+use super::super::types::*;
+use super::super::utility::{read_u32,write_u16,write_u32,push_u16,push_u32};
+
+/// Offset of `size_of_initialized_data` in both optional header formats
+const SIZE_OF_INITIALIZED_DATA_OFFSET: usize = 8;
+/// Offset of `size_of_image` in both optional header formats
+const SIZE_OF_IMAGE_OFFSET: usize = 56;
+/// Offset of `check_sum` in both optional header formats
+const CHECK_SUM_OFFSET: usize = 64;
+/// Offsets of `virtual_size`, `virtual_address`, `size_of_raw_data` and
+/// `pointer_to_raw_data` in `SectionHeader`
+const SECTION_VIRTUAL_SIZE_OFFSET: usize = 8;
+const SECTION_VIRTUAL_ADDRESS_OFFSET: usize = 12;
+const SECTION_SIZE_OF_RAW_DATA_OFFSET: usize = 16;
+const SECTION_POINTER_TO_RAW_DATA_OFFSET: usize = 20;
+/// Offsets of `number_of_sections` and `pointer_to_symbol_table` in
+/// `PeHeader`
+const NUMBER_OF_SECTIONS_OFFSET: usize = 6;
+const POINTER_TO_SYMBOL_TABLE_OFFSET: usize = 12;
+/// The size of `IMAGE_DEBUG_DIRECTORY`, and the offset of its
+/// `PointerToRawData`
+const DEBUG_DIRECTORY_SIZE: usize = 28;
+const DEBUG_POINTER_TO_RAW_DATA_OFFSET: usize = 24;
+
+/// Resource data is aligned to this in the section
+const DATA_ALIGNMENT: usize = 8;
+
+struct BuilderEntry<'a> {
+	rtype: ResourceId<'a>,
+	name: ResourceId<'a>,
+	lang: u16,
+	code_page: u32,
+	data: Cow<'a,[u8]>,
+}
+
+/// An in-memory resource tree that can be serialized into a `.rsrc` section
+pub struct ResourceBuilder<'a> {
+	entries: Vec<BuilderEntry<'a>>,
+}
+
+/// Names sort before IDs. Names are sorted case-insensitively, since lookups
+/// are case-insensitive as well.
+fn sort_key(id: &ResourceId) -> (bool,Vec<u16>,u16) {
+	match *id {
+		ResourceId::Name(name) => (false,name.iter().map(|&c|if c>=b'a' as u16 && c<=b'z' as u16 { c-0x20 } else { c }).collect(),0),
+		ResourceId::Id(id) => (true,vec![],id),
+	}
+}
+
+fn directory_size(entries: usize) -> usize {
+	size_of::<ResourceDirectory>()+entries*size_of::<ResourceDirectoryEntry>()
+}
+
+fn align(value: usize, alignment: usize) -> usize {
+	if alignment<=1 { value } else { (value+alignment-1)/alignment*alignment }
+}
+
+fn push_directory(out: &mut Vec<u8>, ids: &[ResourceId]) {
+	let named=ids.iter().filter(|id|match **id { ResourceId::Name(_) => true, _ => false }).count();
+	push_u32(out,0);
+	push_u32(out,0);
+	push_u16(out,0);
+	push_u16(out,0);
+	push_u16(out,named as u16);
+	push_u16(out,(ids.len()-named) as u16);
+}
+
+fn push_directory_entry(out: &mut Vec<u8>, id: &ResourceId, string_offset: usize, offset: u32) {
+	match *id {
+		ResourceId::Name(_) => push_u32(out,RESOURCE_NAME_IS_STRING|string_offset as u32),
+		ResourceId::Id(id) => push_u32(out,id as u32),
+	}
+	push_u32(out,offset);
+}
+
+impl<'a> ResourceBuilder<'a> {
+	pub fn new() -> ResourceBuilder<'a> {
+		ResourceBuilder{entries:vec![]}
+	}
+
+	/// Start with all resources of an image. The data is not copied.
+	pub fn from_resources<'pe>(resources: &Resources<'pe,'a>) -> Result<ResourceBuilder<'a>> {
+		let mut entries=vec![];
+		for resource in try!(resources.list()) {
+			entries.push(BuilderEntry{
+				rtype:resource.rtype,
+				name:resource.name,
+				lang:resource.lang,
+				code_page:resource.data.get_code_page(),
+				data:Cow::Borrowed(try!(resource.data.get_data())),
+			});
+		}
+		Ok(ResourceBuilder{entries:entries})
+	}
+
+	/// Add a resource, or replace the data of an existing resource with the
+	/// same path. New resources have code page 0.
+	pub fn set<D: Into<Cow<'a,[u8]>>>(&mut self, rtype: ResourceId<'a>, name: ResourceId<'a>, lang: u16, data: D) {
+		let data=data.into();
+		for entry in &mut self.entries {
+			if entry.rtype.matches(&rtype) && entry.name.matches(&name) && entry.lang==lang {
+				entry.data=data;
+				return;
+			}
+		}
+		self.entries.push(BuilderEntry{rtype:rtype,name:name,lang:lang,code_page:0,data:data});
+	}
+
+	/// Remove a resource in language `lang`, or in all languages if `lang`
+	/// is `None`. Returns whether anything was removed.
+	pub fn remove(&mut self, rtype: ResourceId, name: ResourceId, lang: Option<u16>) -> bool {
+		let before=self.entries.len();
+		self.entries.retain(|entry|!(entry.rtype.matches(&rtype) && entry.name.matches(&name) && lang.map_or(true,|lang|entry.lang==lang)));
+		self.entries.len()!=before
+	}
+
+	/// Serialize the tree into a section that will be loaded at `rva`. The
+	/// directories come first, followed by the data entries, the names and
+	/// the data.
+	pub fn build(&self, rva: u32) -> Vec<u8> {
+		let mut entries: Vec<&BuilderEntry>=self.entries.iter().collect();
+		entries.sort_by(|a,b|(sort_key(&a.rtype),sort_key(&a.name),a.lang).cmp(&(sort_key(&b.rtype),sort_key(&b.name),b.lang)));
+
+		// Group by type and name
+		let mut types: Vec<(ResourceId,Vec<(ResourceId,Vec<&BuilderEntry>)>)>=vec![];
+		for entry in entries {
+			if types.last().map_or(true,|&(ref rtype,_)|!rtype.matches(&entry.rtype)) {
+				types.push((entry.rtype,vec![]));
+			}
+			let names=&mut types.last_mut().unwrap().1;
+			if names.last().map_or(true,|&(ref name,_)|!name.matches(&entry.name)) {
+				names.push((entry.name,vec![]));
+			}
+			names.last_mut().unwrap().1.push(entry);
+		}
+
+		// Assign offsets, in the same order as the layout below
+		let mut pos=directory_size(types.len());
+		let mut type_dirs=vec![];
+		for &(_,ref names) in &types {
+			type_dirs.push(pos);
+			pos+=directory_size(names.len());
+		}
+		let mut name_dirs=vec![];
+		for &(_,ref names) in &types {
+			for &(_,ref langs) in names {
+				name_dirs.push(pos);
+				pos+=directory_size(langs.len());
+			}
+		}
+		let data_entries=pos;
+		pos+=self.entries.len()*size_of::<ResourceDataEntry>();
+		let (mut type_strings,mut name_strings)=(vec![],vec![]);
+		for &(ref rtype,ref names) in &types {
+			type_strings.push(pos);
+			if let ResourceId::Name(name)=*rtype {
+				pos+=2+name.len()*2;
+			}
+			for &(ref name,_) in names {
+				name_strings.push(pos);
+				if let ResourceId::Name(name)=*name {
+					pos+=2+name.len()*2;
+				}
+			}
+		}
+		let mut data=vec![];
+		for &(_,ref names) in &types {
+			for &(_,ref langs) in names {
+				for entry in langs {
+					pos=align(pos,DATA_ALIGNMENT);
+					data.push(pos);
+					pos+=entry.data.len();
+				}
+			}
+		}
+
+		let mut out=Vec::with_capacity(pos);
+		push_directory(&mut out,&types.iter().map(|&(rtype,_)|rtype).collect::<Vec<_>>());
+		for (i,&(ref rtype,_)) in types.iter().enumerate() {
+			push_directory_entry(&mut out,rtype,type_strings[i],RESOURCE_DATA_IS_DIRECTORY|type_dirs[i] as u32);
+		}
+		let mut name_index=0;
+		for &(_,ref names) in &types {
+			push_directory(&mut out,&names.iter().map(|&(name,_)|name).collect::<Vec<_>>());
+			for &(ref name,_) in names {
+				push_directory_entry(&mut out,name,name_strings[name_index],RESOURCE_DATA_IS_DIRECTORY|name_dirs[name_index] as u32);
+				name_index+=1;
+			}
+		}
+		let mut data_index=0;
+		for &(_,ref names) in &types {
+			for &(_,ref langs) in names {
+				push_directory(&mut out,&langs.iter().map(|entry|ResourceId::Id(entry.lang)).collect::<Vec<_>>());
+				for entry in langs {
+					push_directory_entry(&mut out,&ResourceId::Id(entry.lang),0,(data_entries+data_index*size_of::<ResourceDataEntry>()) as u32);
+					data_index+=1;
+				}
+			}
+		}
+		let mut data_index=0;
+		for &(_,ref names) in &types {
+			for &(_,ref langs) in names {
+				for entry in langs {
+					push_u32(&mut out,rva+data[data_index] as u32);
+					push_u32(&mut out,entry.data.len() as u32);
+					push_u32(&mut out,entry.code_page);
+					push_u32(&mut out,0);
+					data_index+=1;
+				}
+			}
+		}
+		for &(ref rtype,ref names) in &types {
+			for id in Some(rtype).into_iter().chain(names.iter().map(|&(ref name,_)|name)) {
+				if let ResourceId::Name(name)=*id {
+					push_u16(&mut out,name.len() as u16);
+					for &c in name {
+						push_u16(&mut out,c);
+					}
+				}
+			}
+		}
+		let mut data_index=0;
+		for &(_,ref names) in &types {
+			for &(_,ref langs) in names {
+				for entry in langs {
+					out.resize(data[data_index],0);
+					out.extend_from_slice(&entry.data);
+					data_index+=1;
+				}
+			}
+		}
+		out
+	}
+}
+
+/// Replace the resources of an image in file layout. Returns the new image.
+///
+/// The resources are written over the existing resource section if it holds
+/// nothing but the resources, and they fit or it is the last section in the
+/// file. Otherwise, they are written to a new `.rsrc` section at the end of
+/// the image, and the old section is left as it is. Data after the last
+/// section, such as a certificate table, debug data or a COFF symbol table,
+/// is kept and the file positions pointing to it are updated. The checksum
+/// is cleared, and signatures are invalidated.
+pub fn replace_resources(image: &[u8], resources: &ResourceBuilder) -> Result<Vec<u8>> {
+	let pe=try!(Pe::new(image));
+	let offset_of=|r: *const u8| r as usize-image.as_ptr() as usize;
+	let (oh,section_alignment,file_alignment,size_of_headers,size_of_image)=match pe.get_optional_header() {
+		PeOptionalHeader::Pe32(oh) => (oh as *const _ as *const u8,oh.section_alignment as usize,oh.file_alignment as usize,oh.size_of_headers as usize,oh.size_of_image),
+		PeOptionalHeader::Pe32Plus(oh) => (oh as *const _ as *const u8,oh.section_alignment as usize,oh.file_alignment as usize,oh.size_of_headers as usize,oh.size_of_image),
+	};
+	let oh=offset_of(oh);
+	let ddir=try!(pe.get_directory::<ResourceDirectory>());
+	let ddir_pos=offset_of(ddir as *const _ as *const u8);
+	let sections=pe.get_sections();
+	let section_pos=|i: usize| offset_of(sections.as_ptr() as *const u8)+i*size_of::<SectionHeader>();
+	let sections_end=sections.iter().map(|s|s.pointer_to_raw_data.get() as usize+s.size_of_raw_data as usize).max().unwrap_or(size_of_headers);
+	if sections_end>image.len() {
+		return Err(Error::InvalidSize);
+	}
+
+	// Find where the resources go: (section index, or None for a new one,
+	// virtual address, file position, current raw size)
+	// A section that also holds other data can't be overwritten
+	let existing=if ddir.virtual_address.get()==0 { None } else {
+		sections.iter().position(|s|s.virtual_address.get()==ddir.virtual_address.get() && ddir.size>=s.virtual_size)
+	};
+	let len=resources.build(0).len();
+	let fits=existing.map(|i|{
+		let section=&sections[i];
+		let va=section.virtual_address.get();
+		let virtual_limit=sections.iter().map(|s|s.virtual_address.get()).filter(|&other|other>va).min();
+		let last_in_file=section.pointer_to_raw_data.get() as usize+section.size_of_raw_data as usize==sections_end;
+		virtual_limit.map_or(true,|limit|va as usize+len<=limit as usize) && (last_in_file || len<=section.size_of_raw_data as usize)
+	}).unwrap_or(false);
+	let (index,va,raw_pos,old_raw_size)=if fits {
+		let section=&sections[existing.unwrap()];
+		(existing,section.virtual_address.get(),section.pointer_to_raw_data.get() as usize,section.size_of_raw_data as usize)
+	} else {
+		let first_raw=sections.iter().map(|s|s.pointer_to_raw_data.get() as usize).filter(|&p|p!=0).min().unwrap_or(size_of_headers);
+		if section_pos(sections.len()+1)>min(size_of_headers,first_raw) {
+			return Err(Error::NoHeaderSpace);
+		}
+		let virtual_end=sections.iter().map(|s|s.virtual_address.get() as usize+max(s.virtual_size,s.size_of_raw_data) as usize).max().unwrap_or(size_of_headers);
+		(None,align(virtual_end,section_alignment) as u32,align(sections_end,file_alignment),0)
+	};
+
+	let data=resources.build(va);
+	let raw_size=if index.is_some() && data.len()<=old_raw_size { old_raw_size } else { align(data.len(),file_alignment) };
+	// Everything after the replaced raw data moves by this much
+	let (splice_start,splice_end)=if index.is_some() { (raw_pos,raw_pos+old_raw_size) } else { (sections_end,sections_end) };
+	let mut out=Vec::with_capacity(image.len()+raw_size);
+	out.extend_from_slice(&image[..splice_start]);
+	out.resize(raw_pos,0);
+	out.extend_from_slice(&data);
+	out.resize(raw_pos+raw_size,0);
+	out.extend_from_slice(&image[splice_end..]);
+	let shift=out.len() as isize-image.len() as isize;
+
+	let header=match index {
+		Some(i) => section_pos(i),
+		None => {
+			let header=section_pos(sections.len());
+			for b in &mut out[header..header+size_of::<SectionHeader>()] {
+				*b=0;
+			}
+			out[header..header+8].copy_from_slice(b".rsrc\0\0\0");
+			write_u32(&mut out,header+SECTION_VIRTUAL_ADDRESS_OFFSET,va);
+			write_u32(&mut out,header+SECTION_POINTER_TO_RAW_DATA_OFFSET,raw_pos as u32);
+			let characteristics=section_characteristics::IMAGE_SCN_CNT_INITIALIZED_DATA|section_characteristics::IMAGE_SCN_MEM_READ;
+			write_u32(&mut out,header+size_of::<SectionHeader>()-4,characteristics.bits());
+			let pe_header=offset_of(pe.get_header() as *const _ as *const u8);
+			write_u16(&mut out,pe_header+NUMBER_OF_SECTIONS_OFFSET,sections.len() as u16+1);
+			header
+		},
+	};
+	write_u32(&mut out,header+SECTION_VIRTUAL_SIZE_OFFSET,data.len() as u32);
+	write_u32(&mut out,header+SECTION_SIZE_OF_RAW_DATA_OFFSET,raw_size as u32);
+	write_u32(&mut out,ddir_pos,va);
+	write_u32(&mut out,ddir_pos+4,data.len() as u32);
+	let size_of_initialized_data=match pe.get_optional_header() {
+		PeOptionalHeader::Pe32(oh) => oh.size_of_initialized_data,
+		PeOptionalHeader::Pe32Plus(oh) => oh.size_of_initialized_data,
+	};
+	write_u32(&mut out,oh+SIZE_OF_INITIALIZED_DATA_OFFSET,size_of_initialized_data.wrapping_add((raw_size-old_raw_size) as u32));
+	write_u32(&mut out,oh+SIZE_OF_IMAGE_OFFSET,max(size_of_image,align(va as usize+data.len(),section_alignment) as u32));
+	write_u32(&mut out,oh+CHECK_SUM_OFFSET,0);
+
+	// Update the file positions of data after the replaced raw data. The
+	// headers and all other sections come before it.
+	let moved=|pos: usize| if pos!=0 && pos>=splice_end { Some((pos as isize+shift) as u32) } else { None };
+	if let Ok(certificates)=pe.get_directory_raw(DirectoryEntry::CertificateTable) {
+		if let Some(pos)=moved(certificates.virtual_address.get() as usize) {
+			write_u32(&mut out,offset_of(certificates as *const _ as *const u8),pos);
+		}
+	}
+	if let Ok(debug)=pe.get_directory_raw(DirectoryEntry::Debug) {
+		if debug.virtual_address.get()!=0 {
+			let debug_pos=try!(pe.resolve_rva_slice(debug.virtual_address,debug.size)).get() as usize;
+			for i in 0..debug.size as usize/DEBUG_DIRECTORY_SIZE {
+				let field=debug_pos+i*DEBUG_DIRECTORY_SIZE+DEBUG_POINTER_TO_RAW_DATA_OFFSET;
+				if let Some(pos)=moved(try!(read_u32(image,field)) as usize) {
+					write_u32(&mut out,field,pos);
+				}
+			}
+		}
+	}
+	let pe_header=offset_of(pe.get_header() as *const _ as *const u8);
+	if let Some(pos)=moved(pe.get_header().pointer_to_symbol_table.get() as usize) {
+		write_u32(&mut out,pe_header+POINTER_TO_SYMBOL_TABLE_OFFSET,pos);
+	}
+	Ok(out)
+}
@@ -0,0 +1,249 @@
+/*
+ * Zero-copy Portable Executable parser
+ *
+ * (C) Copyright 2016 Jethro G. Beekman
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; version 2
+ * of the License.
+ */
+
+//! Dialog template (`RT_DIALOG`) decoding. Both `DLGTEMPLATE` and
+//! `DLGTEMPLATEEX` are supported.
+
+use super::{ResourceId,RT_DIALOG,read_utf16z,align4};
+use super::super::{Pe,Error,Result};
+use super::super::utility::{read_u16,read_u32};
+
+/// The dialog template has font information
+pub const DS_SETFONT: u32 = 0x40;
+pub const DS_SHELLFONT: u32 = 0x48;
+
+/// The `signature` of a `DLGTEMPLATEEX`
+const DIALOG_EX_SIGNATURE: u16 = 0xffff;
+/// Marks an ordinal in a name or ordinal field
+const ORDINAL_MARKER: u16 = 0xffff;
+
+/// A name or ordinal (`sz_Or_Ord`) field
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum NameOrOrdinal {
+	None,
+	Ordinal(u16),
+	Name(String),
+}
+
+/// The window class of a dialog control
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum ControlClass {
+	Button,
+	Edit,
+	Static,
+	ListBox,
+	ScrollBar,
+	ComboBox,
+	/// A predefined class not listed above
+	Ordinal(u16),
+	/// A registered class, such as `SysListView32`
+	Name(String),
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct DialogFont {
+	pub point_size: u16,
+	/// Extended templates only
+	pub weight: u16,
+	/// Extended templates only
+	pub italic: bool,
+	/// Extended templates only
+	pub charset: u8,
+	pub typeface: String,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct DialogControl {
+	/// Extended templates only
+	pub help_id: u32,
+	pub style: u32,
+	pub ex_style: u32,
+	pub x: i16,
+	pub y: i16,
+	pub cx: i16,
+	pub cy: i16,
+	/// Standard templates only have 16-bit IDs
+	pub id: u32,
+	pub class: ControlClass,
+	/// The text, or a resource ordinal for controls such as icons
+	pub title: NameOrOrdinal,
+	/// Creation data passed to the control
+	pub extra: Vec<u8>,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Dialog {
+	/// Whether this is a `DLGTEMPLATEEX`
+	pub extended: bool,
+	/// Extended templates only
+	pub help_id: u32,
+	pub style: u32,
+	pub ex_style: u32,
+	pub x: i16,
+	pub y: i16,
+	pub cx: i16,
+	pub cy: i16,
+	pub menu: NameOrOrdinal,
+	pub class: NameOrOrdinal,
+	pub caption: String,
+	/// `None` unless the style includes `DS_SETFONT`
+	pub font: Option<DialogFont>,
+	pub controls: Vec<DialogControl>,
+}
+
+/// Read a name or ordinal field. Returns the field and the position after it.
+fn read_name(data: &[u8], pos: usize) -> Result<(NameOrOrdinal,usize)> {
+	match try!(read_u16(data,pos)) {
+		0 => Ok((NameOrOrdinal::None,pos+2)),
+		ORDINAL_MARKER => Ok((NameOrOrdinal::Ordinal(try!(read_u16(data,pos+2))),pos+4)),
+		_ => {
+			let (name,end)=read_utf16z(data,pos,data.len());
+			Ok((NameOrOrdinal::Name(name),end))
+		},
+	}
+}
+
+fn read_string(data: &[u8], pos: usize) -> Result<(String,usize)> {
+	if pos>=data.len() {
+		return Err(Error::InvalidSize);
+	}
+	Ok(read_utf16z(data,pos,data.len()))
+}
+
+fn control_class(name: NameOrOrdinal) -> Result<ControlClass> {
+	Ok(match name {
+		NameOrOrdinal::Ordinal(0x80) => ControlClass::Button,
+		NameOrOrdinal::Ordinal(0x81) => ControlClass::Edit,
+		NameOrOrdinal::Ordinal(0x82) => ControlClass::Static,
+		NameOrOrdinal::Ordinal(0x83) => ControlClass::ListBox,
+		NameOrOrdinal::Ordinal(0x84) => ControlClass::ScrollBar,
+		NameOrOrdinal::Ordinal(0x85) => ControlClass::ComboBox,
+		NameOrOrdinal::Ordinal(ordinal) => ControlClass::Ordinal(ordinal),
+		NameOrOrdinal::Name(name) => ControlClass::Name(name),
+		NameOrOrdinal::None => return Err(Error::InvalidResource),
+	})
+}
+
+/// Parse a `DLGITEMTEMPLATE` or `DLGITEMTEMPLATEEX` at `pos`. Returns the
+/// control and the position after it.
+fn parse_control(data: &[u8], mut pos: usize, extended: bool) -> Result<(DialogControl,usize)> {
+	let (help_id,style,ex_style)=if extended {
+		let help_id=try!(read_u32(data,pos));
+		let ex_style=try!(read_u32(data,pos+4));
+		let style=try!(read_u32(data,pos+8));
+		pos+=12;
+		(help_id,style,ex_style)
+	} else {
+		let style=try!(read_u32(data,pos));
+		let ex_style=try!(read_u32(data,pos+4));
+		pos+=8;
+		(0,style,ex_style)
+	};
+	let x=try!(read_u16(data,pos)) as i16;
+	let y=try!(read_u16(data,pos+2)) as i16;
+	let cx=try!(read_u16(data,pos+4)) as i16;
+	let cy=try!(read_u16(data,pos+6)) as i16;
+	pos+=8;
+	let id=if extended {
+		pos+=4;
+		try!(read_u32(data,pos-4))
+	} else {
+		pos+=2;
+		try!(read_u16(data,pos-2)) as u32
+	};
+	let (class,pos)=try!(read_name(data,pos));
+	let (title,pos)=try!(read_name(data,pos));
+	// The size does not include the size field itself
+	let extra_size=try!(read_u16(data,pos)) as usize;
+	let extra=try!(data.get(pos+2..pos+2+extra_size).ok_or(Error::InvalidSize));
+	Ok((DialogControl{
+		help_id:help_id,
+		style:style,
+		ex_style:ex_style,
+		x:x,
+		y:y,
+		cx:cx,
+		cy:cy,
+		id:id,
+		class:try!(control_class(class)),
+		title:title,
+		extra:extra.to_owned(),
+	},pos+2+extra_size))
+}
+
+impl Dialog {
+	/// Parse the data of an `RT_DIALOG` resource
+	pub fn parse(data: &[u8]) -> Result<Dialog> {
+		let extended=try!(read_u16(data,0))==1 && try!(read_u16(data,2))==DIALOG_EX_SIGNATURE;
+		let (help_id,style,ex_style,mut pos)=if extended {
+			(try!(read_u32(data,4)),try!(read_u32(data,12)),try!(read_u32(data,8)),16)
+		} else {
+			(0,try!(read_u32(data,0)),try!(read_u32(data,4)),8)
+		};
+		let count=try!(read_u16(data,pos));
+		let x=try!(read_u16(data,pos+2)) as i16;
+		let y=try!(read_u16(data,pos+4)) as i16;
+		let cx=try!(read_u16(data,pos+6)) as i16;
+		let cy=try!(read_u16(data,pos+8)) as i16;
+		pos+=10;
+		let (menu,pos)=try!(read_name(data,pos));
+		let (class,pos)=try!(read_name(data,pos));
+		let (caption,mut pos)=try!(read_string(data,pos));
+		let font=if style&DS_SETFONT!=0 {
+			let point_size=try!(read_u16(data,pos));
+			let (weight,italic,charset)=if extended {
+				let weight=try!(read_u16(data,pos+2));
+				let italic=*try!(data.get(pos+4).ok_or(Error::InvalidSize));
+				let charset=*try!(data.get(pos+5).ok_or(Error::InvalidSize));
+				pos+=6;
+				(weight,italic!=0,charset)
+			} else {
+				pos+=2;
+				(0,false,0)
+			};
+			let (typeface,end)=try!(read_string(data,pos));
+			pos=end;
+			Some(DialogFont{point_size:point_size,weight:weight,italic:italic,charset:charset,typeface:typeface})
+		} else {
+			None
+		};
+
+		let mut controls=vec![];
+		for _ in 0..count {
+			let (control,end)=try!(parse_control(data,align4(pos),extended));
+			controls.push(control);
+			pos=end;
+		}
+		Ok(Dialog{
+			extended:extended,
+			help_id:help_id,
+			style:style,
+			ex_style:ex_style,
+			x:x,
+			y:y,
+			cx:cx,
+			cy:cy,
+			menu:menu,
+			class:class,
+			caption:caption,
+			font:font,
+			controls:controls,
+		})
+	}
+}
+
+impl<'data> Pe<'data> {
+	/// Decode an `RT_DIALOG` resource. See `Resources::find` for how the
+	/// language is chosen.
+	pub fn get_dialog(&self, name: ResourceId, lang: Option<u16>) -> Result<Dialog> {
+		Dialog::parse(try!(self.find_resource(RT_DIALOG,name,lang)))
+	}
+}
@@ -0,0 +1,158 @@
+/*
+ * Zero-copy Portable Executable parser
+ *
+ * (C) Copyright 2016 Jethro G. Beekman
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; version 2
+ * of the License.
+ */
+
+//! Icon and cursor group (`RT_GROUP_ICON`, `RT_GROUP_CURSOR`) decoding and
+//! reassembly into .ico and .cur files.
+
+use std::mem::size_of;
+
+use super::{ResourceId,RT_ICON,RT_CURSOR,RT_GROUP_ICON,RT_GROUP_CURSOR};
+use super::super::{Pe,Error,Result};
+use super::super::types::{IconDir,GroupIconDirEntry,GroupCursorDirEntry};
+use super::super::utility::{FP,URP,FPRef,read_u16,push_u16,push_u32};
+
+pub const ICON_TYPE_ICON: u16 = 1;
+pub const ICON_TYPE_CURSOR: u16 = 2;
+
+/// The size of a directory entry in .ico and .cur files
+const FILE_DIR_ENTRY_SIZE: usize = 16;
+/// `RT_CURSOR` resources start with the hotspot coordinates
+const CURSOR_HOTSPOT_SIZE: usize = 4;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct IconGroupEntry {
+	/// In pixels
+	pub width: u16,
+	/// In pixels. For cursors, this is half the stored value, which includes
+	/// the mask.
+	pub height: u16,
+	/// 0 for cursors and for icons with 256 or more colors
+	pub color_count: u8,
+	pub planes: u16,
+	pub bit_count: u16,
+	pub bytes_in_res: u32,
+	/// The name of the `RT_ICON` or `RT_CURSOR` resource with the image
+	pub id: u16,
+}
+
+#[derive(Debug,Clone)]
+pub struct IconGroup {
+	pub cursor: bool,
+	pub entries: Vec<IconGroupEntry>,
+}
+
+/// Icon sizes are stored in a byte, with 0 meaning 256
+fn icon_size(size: u8) -> u16 {
+	if size==0 { 256 } else { size as u16 }
+}
+
+impl IconGroup {
+	/// Parse the data of an `RT_GROUP_ICON` or `RT_GROUP_CURSOR` resource
+	pub fn parse(data: &[u8]) -> Result<IconGroup> {
+		let dir=try!(data.ref_at::<IconDir>(FP::new(0)));
+		let offset=size_of::<IconDir>() as u32;
+		let (cursor,entries)=match dir.icon_type {
+			ICON_TYPE_ICON => (false,try!(data.ref_slice_at::<GroupIconDirEntry>(FP::new(offset),dir.count as u32)).iter().map(|entry|IconGroupEntry{
+				width:icon_size(entry.width),
+				height:icon_size(entry.height),
+				color_count:entry.color_count,
+				planes:entry.planes,
+				bit_count:entry.bit_count,
+				bytes_in_res:entry.bytes_in_res,
+				id:entry.id,
+			}).collect()),
+			ICON_TYPE_CURSOR => (true,try!(data.ref_slice_at::<GroupCursorDirEntry>(FP::new(offset),dir.count as u32)).iter().map(|entry|IconGroupEntry{
+				width:entry.width,
+				height:entry.height/2,
+				color_count:0,
+				planes:entry.planes,
+				bit_count:entry.bit_count,
+				bytes_in_res:entry.bytes_in_res,
+				id:entry.id,
+			}).collect()),
+			_ => return Err(Error::InvalidResource),
+		};
+		Ok(IconGroup{cursor:cursor,entries:entries})
+	}
+
+	/// Build a .ico or .cur file. `images` has the data of the `RT_ICON` or
+	/// `RT_CURSOR` resource for each entry.
+	pub fn build_file(&self, images: &[&[u8]]) -> Result<Vec<u8>> {
+		if images.len()!=self.entries.len() {
+			return Err(Error::InvalidResource);
+		}
+		let mut out=vec![];
+		push_u16(&mut out,0);
+		push_u16(&mut out,if self.cursor { ICON_TYPE_CURSOR } else { ICON_TYPE_ICON });
+		push_u16(&mut out,self.entries.len() as u16);
+		let mut offset=size_of::<IconDir>()+FILE_DIR_ENTRY_SIZE*self.entries.len();
+		let mut bitmaps=vec![];
+		for (entry,&image) in self.entries.iter().zip(images) {
+			// For cursors, the planes and bit count are replaced by the hotspot
+			let (bitmap,planes,bit_count)=if self.cursor {
+				if image.len()<CURSOR_HOTSPOT_SIZE {
+					return Err(Error::InvalidResource);
+				}
+				(&image[CURSOR_HOTSPOT_SIZE..],try!(read_u16(image,0)),try!(read_u16(image,2)))
+			} else {
+				(image,entry.planes,entry.bit_count)
+			};
+			out.push(entry.width as u8);
+			out.push(entry.height as u8);
+			out.push(entry.color_count);
+			out.push(0);
+			push_u16(&mut out,planes);
+			push_u16(&mut out,bit_count);
+			push_u32(&mut out,bitmap.len() as u32);
+			push_u32(&mut out,offset as u32);
+			offset+=bitmap.len();
+			bitmaps.push(bitmap);
+		}
+		for bitmap in bitmaps {
+			out.extend_from_slice(bitmap);
+		}
+		Ok(out)
+	}
+}
+
+impl<'data> Pe<'data> {
+	fn get_group_file(&self, group_type: ResourceId, image_type: ResourceId, name: ResourceId, lang: Option<u16>) -> Result<Vec<u8>> {
+		let resources=try!(self.get_resources());
+		let group_resource=try!(resources.find(group_type,name,lang));
+		let group=try!(IconGroup::parse(try!(group_resource.data.get_data())));
+		let mut images=vec![];
+		for entry in &group.entries {
+			let image=try!(resources.find(image_type,ResourceId::Id(entry.id),Some(group_resource.lang)));
+			images.push(try!(image.data.get_data()));
+		}
+		group.build_file(&images)
+	}
+
+	pub fn get_icon_group(&self, name: ResourceId, lang: Option<u16>) -> Result<IconGroup> {
+		IconGroup::parse(try!(self.find_resource(RT_GROUP_ICON,name,lang)))
+	}
+
+	pub fn get_cursor_group(&self, name: ResourceId, lang: Option<u16>) -> Result<IconGroup> {
+		IconGroup::parse(try!(self.find_resource(RT_GROUP_CURSOR,name,lang)))
+	}
+
+	/// Get an icon group as a .ico file. The images are taken from the same
+	/// language as the group.
+	pub fn get_icon_file(&self, name: ResourceId, lang: Option<u16>) -> Result<Vec<u8>> {
+		self.get_group_file(RT_GROUP_ICON,RT_ICON,name,lang)
+	}
+
+	/// Get a cursor group as a .cur file. The images are taken from the same
+	/// language as the group.
+	pub fn get_cursor_file(&self, name: ResourceId, lang: Option<u16>) -> Result<Vec<u8>> {
+		self.get_group_file(RT_GROUP_CURSOR,RT_CURSOR,name,lang)
+	}
+}
@@ -0,0 +1,317 @@
+/*
+ * Zero-copy Portable Executable parser
+ *
+ * (C) Copyright 2016 Jethro G. Beekman
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; version 2
+ * of the License.
+ */
+
+//! Application manifest (`RT_MANIFEST`) extraction and parsing. Only the
+//! parts of the XML that the loader cares about are decoded.
+
+use super::{ResourceId,RT_MANIFEST};
+use super::super::{Pe,Error,Result};
+use super::super::utility::{read_u16,read_u16_be};
+
+pub const CREATEPROCESS_MANIFEST_RESOURCE_ID: u16 = 1;
+pub const ISOLATIONAWARE_MANIFEST_RESOURCE_ID: u16 = 2;
+pub const ISOLATIONAWARE_NOSTATICIMPORT_MANIFEST_RESOURCE_ID: u16 = 3;
+
+/// `supportedOS` IDs and the Windows versions they stand for
+pub const SUPPORTED_OS_IDS: &'static [(&'static str,&'static str)] = &[
+	("{e2011457-1546-43c5-a5fe-008deee3d3f0}","Windows Vista"),
+	("{35138b9a-5d96-4fbd-8e2d-a2440225f93a}","Windows 7"),
+	("{4a2f28e3-53b9-4441-ba9c-d69d4a4a6e38}","Windows 8"),
+	("{1f676c76-80e1-4239-95bb-83d0f6d0da78}","Windows 8.1"),
+	("{8e0f7a12-bfb3-4fe8-b9a5-48fd50a15a9a}","Windows 10"),
+];
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum ExecutionLevel {
+	AsInvoker,
+	HighestAvailable,
+	RequireAdministrator,
+	Other(String),
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct RequestedExecutionLevel {
+	pub level: ExecutionLevel,
+	pub ui_access: bool,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq,Default)]
+pub struct AssemblyIdentity {
+	pub assembly_type: Option<String>,
+	pub name: Option<String>,
+	pub version: Option<String>,
+	pub processor_architecture: Option<String>,
+	pub public_key_token: Option<String>,
+	pub language: Option<String>,
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct Manifest {
+	pub identity: Option<AssemblyIdentity>,
+	pub requested_execution_level: Option<RequestedExecutionLevel>,
+	/// The text of the `dpiAware` setting, such as `true` or `true/pm`
+	pub dpi_aware: Option<String>,
+	/// The text of the `dpiAwareness` setting
+	pub dpi_awareness: Option<String>,
+	/// `supportedOS` IDs, see `SUPPORTED_OS_IDS`
+	pub supported_os: Vec<String>,
+	/// Side-by-side assemblies from `dependentAssembly` elements
+	pub dependencies: Vec<AssemblyIdentity>,
+}
+
+/// An XML element. Namespace prefixes are removed from element and
+/// attribute names.
+#[derive(Debug,Default)]
+struct Element {
+	name: String,
+	attributes: Vec<(String,String)>,
+	children: Vec<Element>,
+	text: String,
+}
+
+impl Element {
+	fn attribute(&self, name: &str) -> Option<&str> {
+		self.attributes.iter().find(|&&(ref key,_)|key==name).map(|&(_,ref value)|&**value)
+	}
+
+	fn child(&self, name: &str) -> Option<&Element> {
+		self.children.iter().find(|child|child.name==name)
+	}
+
+	/// All descendants with this name, in document order
+	fn descendants<'a>(&'a self, name: &str, out: &mut Vec<&'a Element>) {
+		for child in &self.children {
+			if child.name==name {
+				out.push(child);
+			}
+			child.descendants(name,out);
+		}
+	}
+
+	fn find(&self, name: &str) -> Option<&Element> {
+		let mut out=vec![];
+		self.descendants(name,&mut out);
+		out.into_iter().next()
+	}
+}
+
+fn local_name(name: &str) -> &str {
+	match name.rfind(':') {
+		Some(pos) => &name[pos+1..],
+		None => name,
+	}
+}
+
+fn decode_entities(s: &str) -> Result<String> {
+	let mut out=String::new();
+	let mut rest=s;
+	while let Some(pos)=rest.find('&') {
+		out.push_str(&rest[..pos]);
+		rest=&rest[pos+1..];
+		let end=try!(rest.find(';').ok_or(Error::InvalidResource));
+		let c=match &rest[..end] {
+			"lt" => '<',
+			"gt" => '>',
+			"amp" => '&',
+			"quot" => '"',
+			"apos" => '\'',
+			entity if entity.starts_with("#x") => try!(u32::from_str_radix(&entity[2..],16).ok().and_then(::std::char::from_u32).ok_or(Error::InvalidResource)),
+			entity if entity.starts_with('#') => try!(entity[1..].parse::<u32>().ok().and_then(::std::char::from_u32).ok_or(Error::InvalidResource)),
+			_ => return Err(Error::InvalidResource),
+		};
+		out.push(c);
+		rest=&rest[end+1..];
+	}
+	out.push_str(rest);
+	Ok(out)
+}
+
+fn is_name_char(c: char) -> bool {
+	!c.is_whitespace() && c!='/' && c!='>' && c!='=' && c!='<'
+}
+
+/// Parse the attributes and the end of a start tag, returning whether the
+/// element is empty and the rest of the input
+fn parse_attributes<'a>(mut rest: &'a str, element: &mut Element) -> Result<(bool,&'a str)> {
+	loop {
+		rest=rest.trim_left();
+		if rest.starts_with("/>") {
+			return Ok((true,&rest[2..]));
+		} else if rest.starts_with('>') {
+			return Ok((false,&rest[1..]));
+		}
+		let end=rest.find(|c: char|!is_name_char(c)).unwrap_or(rest.len());
+		if end==0 {
+			return Err(Error::InvalidResource);
+		}
+		let name=&rest[..end];
+		rest=rest[end..].trim_left();
+		if !rest.starts_with('=') {
+			return Err(Error::InvalidResource);
+		}
+		rest=rest[1..].trim_left();
+		let quote=try!(rest.chars().next().ok_or(Error::InvalidResource));
+		if quote!='"' && quote!='\'' {
+			return Err(Error::InvalidResource);
+		}
+		let end=try!(rest[1..].find(quote).ok_or(Error::InvalidResource));
+		let value=try!(decode_entities(&rest[1..end+1]));
+		rest=&rest[end+2..];
+		if name!="xmlns" && !name.starts_with("xmlns:") {
+			element.attributes.push((local_name(name).to_owned(),value));
+		}
+	}
+}
+
+/// A minimal non-validating XML parser. Returns the root element.
+fn parse_xml(xml: &str) -> Result<Element> {
+	let mut stack=vec![Element::default()];
+	let mut rest=xml;
+	while !rest.is_empty() {
+		let pos=rest.find('<').unwrap_or(rest.len());
+		let text=try!(decode_entities(&rest[..pos]));
+		stack.last_mut().unwrap().text.push_str(&text);
+		rest=&rest[pos..];
+		if rest.is_empty() {
+			break;
+		}
+		let skip=[("<?","?>"),("<!--","-->"),("<!DOCTYPE",">")].iter().find(|&&(start,_)|rest.starts_with(start)).map(|&(_,end)|end);
+		if let Some(end)=skip {
+			let pos=try!(rest.find(end).ok_or(Error::InvalidResource));
+			rest=&rest[pos+end.len()..];
+		} else if rest.starts_with("<![CDATA[") {
+			let pos=try!(rest.find("]]>").ok_or(Error::InvalidResource));
+			stack.last_mut().unwrap().text.push_str(&rest[9..pos]);
+			rest=&rest[pos+3..];
+		} else if rest.starts_with("</") {
+			let pos=try!(rest.find('>').ok_or(Error::InvalidResource));
+			let name=local_name(rest[2..pos].trim());
+			rest=&rest[pos+1..];
+			if stack.len()<2 || stack.last().unwrap().name!=name {
+				return Err(Error::InvalidResource);
+			}
+			let element=stack.pop().unwrap();
+			stack.last_mut().unwrap().children.push(element);
+		} else {
+			let end=rest[1..].find(|c: char|!is_name_char(c)).map_or(rest.len(),|pos|pos+1);
+			if end==1 {
+				return Err(Error::InvalidResource);
+			}
+			let mut element=Element{name:local_name(&rest[1..end]).to_owned(),..Element::default()};
+			let (empty,after)=try!(parse_attributes(&rest[end..],&mut element));
+			rest=after;
+			if empty {
+				stack.last_mut().unwrap().children.push(element);
+			} else {
+				stack.push(element);
+			}
+		}
+	}
+	if stack.len()!=1 {
+		return Err(Error::InvalidResource);
+	}
+	let mut document=stack.pop().unwrap();
+	if document.children.len()!=1 {
+		return Err(Error::InvalidResource);
+	}
+	Ok(document.children.pop().unwrap())
+}
+
+/// Decode UTF-8, or UTF-16 with a byte order mark
+fn decode_text(data: &[u8]) -> Result<String> {
+	let read_utf16: Option<fn(&[u8],usize) -> Result<u16>>=if data.starts_with(&[0xff,0xfe]) {
+		Some(read_u16)
+	} else if data.starts_with(&[0xfe,0xff]) {
+		Some(read_u16_be)
+	} else {
+		None
+	};
+	if let Some(read)=read_utf16 {
+		let chars=try!((1..data.len()/2).map(|i|read(data,i*2)).collect::<Result<Vec<u16>>>());
+		return String::from_utf16(&chars).map_err(|_|Error::InvalidResource);
+	}
+	let data=if data.starts_with(&[0xef,0xbb,0xbf]) { &data[3..] } else { data };
+	::std::str::from_utf8(data).map(str::to_owned).map_err(|_|Error::InvalidResource)
+}
+
+fn parse_identity(element: &Element) -> AssemblyIdentity {
+	let attribute=|name|element.attribute(name).map(str::to_owned);
+	AssemblyIdentity{
+		assembly_type:attribute("type"),
+		name:attribute("name"),
+		version:attribute("version"),
+		processor_architecture:attribute("processorArchitecture"),
+		public_key_token:attribute("publicKeyToken"),
+		language:attribute("language"),
+	}
+}
+
+fn element_text(root: &Element, name: &str) -> Option<String> {
+	root.find(name).map(|element|element.text.trim().to_owned())
+}
+
+impl Manifest {
+	/// Parse the data of an `RT_MANIFEST` resource
+	pub fn parse(data: &[u8]) -> Result<Manifest> {
+		let root=try!(parse_xml(&try!(decode_text(data))));
+		if root.name!="assembly" {
+			return Err(Error::InvalidResource);
+		}
+		let mut manifest=Manifest::default();
+		manifest.identity=root.child("assemblyIdentity").map(parse_identity);
+		manifest.requested_execution_level=root.find("requestedExecutionLevel").map(|element|{
+			let level=match element.attribute("level").unwrap_or("") {
+				"asInvoker" => ExecutionLevel::AsInvoker,
+				"highestAvailable" => ExecutionLevel::HighestAvailable,
+				"requireAdministrator" => ExecutionLevel::RequireAdministrator,
+				level => ExecutionLevel::Other(level.to_owned()),
+			};
+			let ui_access=element.attribute("uiAccess").map_or(false,|value|value.to_lowercase()=="true");
+			RequestedExecutionLevel{level:level,ui_access:ui_access}
+		});
+		manifest.dpi_aware=element_text(&root,"dpiAware");
+		manifest.dpi_awareness=element_text(&root,"dpiAwareness");
+
+		let mut elements=vec![];
+		root.descendants("supportedOS",&mut elements);
+		manifest.supported_os=elements.iter().filter_map(|element|element.attribute("Id")).map(str::to_owned).collect();
+
+		let mut elements=vec![];
+		root.descendants("dependentAssembly",&mut elements);
+		manifest.dependencies=elements.iter().filter_map(|element|element.child("assemblyIdentity")).map(parse_identity).collect();
+		Ok(manifest)
+	}
+
+	pub fn requires_administrator(&self) -> bool {
+		self.requested_execution_level.as_ref().map_or(false,|level|level.level==ExecutionLevel::RequireAdministrator)
+	}
+}
+
+impl<'data> Pe<'data> {
+	/// Get the raw manifest. The resource IDs used by the loader are tried in
+	/// order: `CREATEPROCESS_MANIFEST_RESOURCE_ID`,
+	/// `ISOLATIONAWARE_MANIFEST_RESOURCE_ID` and
+	/// `ISOLATIONAWARE_NOSTATICIMPORT_MANIFEST_RESOURCE_ID`.
+	pub fn get_manifest_data(&self) -> Result<&'data [u8]> {
+		let ids=[CREATEPROCESS_MANIFEST_RESOURCE_ID,ISOLATIONAWARE_MANIFEST_RESOURCE_ID,ISOLATIONAWARE_NOSTATICIMPORT_MANIFEST_RESOURCE_ID];
+		for &id in &ids {
+			match self.find_resource(RT_MANIFEST,ResourceId::Id(id),None) {
+				Err(Error::ResourceNotFound) => continue,
+				result => return result,
+			}
+		}
+		Err(Error::ResourceNotFound)
+	}
+
+	pub fn get_manifest(&self) -> Result<Manifest> {
+		Manifest::parse(try!(self.get_manifest_data()))
+	}
+}
@@ -0,0 +1,153 @@
+/*
+ * Zero-copy Portable Executable parser
+ *
+ * (C) Copyright 2016 Jethro G. Beekman
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; version 2
+ * of the License.
+ */
+
+//! Menu template (`RT_MENU`) decoding. Both standard and extended templates
+//! are supported.
+
+use super::{ResourceId,RT_MENU,read_utf16z,align4};
+use super::super::{Pe,Error,Result};
+use super::super::utility::{read_u16,read_u32};
+
+pub const MF_GRAYED: u32 = 0x0001;
+pub const MF_DISABLED: u32 = 0x0002;
+pub const MF_CHECKED: u32 = 0x0008;
+pub const MF_MENUBARBREAK: u32 = 0x0020;
+pub const MF_MENUBREAK: u32 = 0x0040;
+pub const MF_HELP: u32 = 0x4000;
+pub const MF_SEPARATOR: u32 = 0x0800;
+pub const MFT_SEPARATOR: u32 = 0x0800;
+
+/// Standard item flag: the item opens a submenu
+const MF_POPUP: u16 = 0x0010;
+/// Standard item flag: the last item of a menu
+const MF_END: u16 = 0x0080;
+/// Extended item flag: the item opens a submenu
+const MFR_POPUP: u16 = 0x0001;
+/// Extended item flag: the last item of a menu
+const MFR_END: u16 = 0x0080;
+
+/// Menus nested deeper than this are rejected
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct MenuItem {
+	/// Standard templates: the `MF_*` options, without `MF_POPUP` and
+	/// `MF_END`. Extended templates: the `MFT_*` type.
+	pub flags: u32,
+	/// Extended templates only: the `MFS_*` state
+	pub state: u32,
+	/// 0 for popups in standard templates
+	pub id: u32,
+	pub text: String,
+	/// Extended templates only, for popups
+	pub help_id: u32,
+	pub popup: bool,
+	/// Items of the submenu, if this is a popup
+	pub children: Vec<MenuItem>,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Menu {
+	/// Whether this is a `MENUEX_TEMPLATE_HEADER` template
+	pub extended: bool,
+	/// Extended templates only
+	pub help_id: u32,
+	pub items: Vec<MenuItem>,
+}
+
+impl MenuItem {
+	/// `MENUITEM SEPARATOR` is stored as an empty item in standard templates
+	pub fn is_separator(&self) -> bool {
+		!self.popup && (self.flags&MF_SEPARATOR!=0 || (self.flags==0 && self.id==0 && self.text.is_empty()))
+	}
+}
+
+fn read_text(data: &[u8], pos: usize) -> Result<(String,usize)> {
+	if pos>=data.len() {
+		return Err(Error::InvalidSize);
+	}
+	Ok(read_utf16z(data,pos,data.len()))
+}
+
+/// Parse the items of a menu at `pos`, up to the one marked as the last.
+/// Returns the items and the position after them.
+fn parse_items(data: &[u8], mut pos: usize, extended: bool, depth: usize) -> Result<(Vec<MenuItem>,usize)> {
+	if depth>MAX_DEPTH {
+		return Err(Error::InvalidResource);
+	}
+	let mut items=vec![];
+	loop {
+		let (mut item,end)=if extended {
+			pos=align4(pos);
+			let item_type=try!(read_u32(data,pos));
+			let state=try!(read_u32(data,pos+4));
+			let id=try!(read_u32(data,pos+8));
+			let flags=try!(read_u16(data,pos+12));
+			let (text,text_end)=try!(read_text(data,pos+14));
+			let popup=flags&MFR_POPUP!=0;
+			let help_id=if popup {
+				pos=align4(text_end)+4;
+				try!(read_u32(data,pos-4))
+			} else {
+				pos=text_end;
+				0
+			};
+			(MenuItem{flags:item_type,state:state,id:id,text:text,help_id:help_id,popup:popup,children:vec![]},flags&MFR_END!=0)
+		} else {
+			let flags=try!(read_u16(data,pos));
+			let popup=flags&MF_POPUP!=0;
+			let id=if popup {
+				pos+=2;
+				0
+			} else {
+				pos+=4;
+				try!(read_u16(data,pos-2))
+			};
+			let (text,text_end)=try!(read_text(data,pos));
+			pos=text_end;
+			let options=(flags&!(MF_POPUP|MF_END)) as u32;
+			(MenuItem{flags:options,state:0,id:id as u32,text:text,help_id:0,popup:popup,children:vec![]},flags&MF_END!=0)
+		};
+		if item.popup {
+			let (children,children_end)=try!(parse_items(data,pos,extended,depth+1));
+			item.children=children;
+			pos=children_end;
+		}
+		items.push(item);
+		if end {
+			return Ok((items,pos));
+		}
+	}
+}
+
+impl Menu {
+	/// Parse the data of an `RT_MENU` resource
+	pub fn parse(data: &[u8]) -> Result<Menu> {
+		let version=try!(read_u16(data,0));
+		// The offset is relative to the end of the offset field
+		let offset=try!(read_u16(data,2)) as usize;
+		let (extended,help_id)=match version {
+			0 => (false,0),
+			1 => (true,try!(read_u32(data,4))),
+			_ => return Err(Error::UnsupportedVersion),
+		};
+		let (items,_)=try!(parse_items(data,4+offset,extended,0));
+		Ok(Menu{extended:extended,help_id:help_id,items:items})
+	}
+}
+
+impl<'data> Pe<'data> {
+	/// Decode an `RT_MENU` resource. See `Resources::find` for how the
+	/// language is chosen.
+	pub fn get_menu(&self, name: ResourceId, lang: Option<u16>) -> Result<Menu> {
+		Menu::parse(try!(self.find_resource(RT_MENU,name,lang)))
+	}
+}
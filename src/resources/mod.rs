@@ -0,0 +1,328 @@
+/*
+ * Zero-copy Portable Executable parser
+ *
+ * (C) Copyright 2016 Jethro G. Beekman
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; version 2
+ * of the License.
+ */
+
+//! Resource directory parsing. Resources are stored in a tree with three
+//! levels: type, name and language.
+
+use std::fmt;
+use std::slice;
+use std::mem::size_of;
+
+use super::{Pe,Error,Result,RVA};
+use super::types::*;
+use super::utility::{URPConvert,read_u16};
+
+pub const RT_CURSOR: ResourceId<'static> = ResourceId::Id(1);
+pub const RT_BITMAP: ResourceId<'static> = ResourceId::Id(2);
+pub const RT_ICON: ResourceId<'static> = ResourceId::Id(3);
+pub const RT_MENU: ResourceId<'static> = ResourceId::Id(4);
+pub const RT_DIALOG: ResourceId<'static> = ResourceId::Id(5);
+pub const RT_STRING: ResourceId<'static> = ResourceId::Id(6);
+pub const RT_FONTDIR: ResourceId<'static> = ResourceId::Id(7);
+pub const RT_FONT: ResourceId<'static> = ResourceId::Id(8);
+pub const RT_ACCELERATOR: ResourceId<'static> = ResourceId::Id(9);
+pub const RT_RCDATA: ResourceId<'static> = ResourceId::Id(10);
+pub const RT_MESSAGETABLE: ResourceId<'static> = ResourceId::Id(11);
+pub const RT_GROUP_CURSOR: ResourceId<'static> = ResourceId::Id(12);
+pub const RT_GROUP_ICON: ResourceId<'static> = ResourceId::Id(14);
+pub const RT_VERSION: ResourceId<'static> = ResourceId::Id(16);
+pub const RT_DLGINCLUDE: ResourceId<'static> = ResourceId::Id(17);
+pub const RT_PLUGPLAY: ResourceId<'static> = ResourceId::Id(19);
+pub const RT_VXD: ResourceId<'static> = ResourceId::Id(20);
+pub const RT_ANICURSOR: ResourceId<'static> = ResourceId::Id(21);
+pub const RT_ANIICON: ResourceId<'static> = ResourceId::Id(22);
+pub const RT_HTML: ResourceId<'static> = ResourceId::Id(23);
+pub const RT_MANIFEST: ResourceId<'static> = ResourceId::Id(24);
+
+pub mod version;
+pub mod manifest;
+pub mod icon;
+pub mod strings;
+pub mod dialog;
+pub mod menu;
+pub mod builder;
+
+pub const LANG_NEUTRAL: u16 = 0x0000;
+pub const LANG_ENGLISH_US: u16 = 0x0409;
+
+/// Identifies a resource type, name or language
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ResourceId<'data> {
+	Id(u16),
+	/// A UTF-16 string, not NULL-terminated
+	Name(&'data [u16]),
+}
+
+impl<'data> ResourceId<'data> {
+	/// Names are compared case-insensitively, like `FindResource` does
+	pub fn matches(&self, other: &ResourceId) -> bool {
+		fn upper(c: u16) -> u16 {
+			if c>=b'a' as u16 && c<=b'z' as u16 { c-0x20 } else { c }
+		}
+		match (*self,*other) {
+			(ResourceId::Id(a),ResourceId::Id(b)) => a==b,
+			(ResourceId::Name(a),ResourceId::Name(b)) => a.len()==b.len() && a.iter().zip(b).all(|(&a,&b)|upper(a)==upper(b)),
+			_ => false,
+		}
+	}
+}
+
+impl<'data> fmt::Display for ResourceId<'data> {
+	/// Names are displayed as-is, IDs as `#<id>`
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			ResourceId::Id(id) => write!(f,"#{}",id),
+			ResourceId::Name(name) => write!(f,"{}",String::from_utf16_lossy(name)),
+		}
+	}
+}
+
+/// Data in resources is aligned relative to the start of the resource
+fn align4(pos: usize) -> usize {
+	(pos+3)&!3
+}
+
+/// Read a NULL-terminated UTF-16 string that ends before `end`. Returns the
+/// string and the position after the terminator. A missing terminator is
+/// not an error.
+fn read_utf16z(data: &[u8], mut pos: usize, end: usize) -> (String,usize) {
+	let data=&data[..::std::cmp::min(end,data.len())];
+	let mut chars=vec![];
+	while let Ok(c)=read_u16(data,pos) {
+		pos+=2;
+		if c==0 {
+			break;
+		}
+		chars.push(c);
+	}
+	(String::from_utf16_lossy(&chars),pos)
+}
+
+#[derive(Clone,Copy)]
+pub struct Resources<'pe,'data: 'pe> {
+	pe: &'pe Pe<'data>,
+	base: RVA<ResourceDirectory>,
+}
+
+#[derive(Clone,Copy)]
+pub struct ResourceDir<'pe,'data: 'pe> {
+	resources: Resources<'pe,'data>,
+	dir: &'data ResourceDirectory,
+	entries: &'data [ResourceDirectoryEntry],
+}
+
+pub struct ResourceEntryIter<'pe,'data: 'pe> {
+	resources: Resources<'pe,'data>,
+	entries: slice::Iter<'data,ResourceDirectoryEntry>,
+}
+
+#[derive(Clone,Copy)]
+pub struct ResourceEntry<'pe,'data: 'pe> {
+	resources: Resources<'pe,'data>,
+	entry: &'data ResourceDirectoryEntry,
+}
+
+pub enum ResourceNode<'pe,'data: 'pe> {
+	Directory(ResourceDir<'pe,'data>),
+	Data(ResourceData<'pe,'data>),
+}
+
+#[derive(Clone,Copy)]
+pub struct ResourceData<'pe,'data: 'pe> {
+	pe: &'pe Pe<'data>,
+	entry: &'data ResourceDataEntry,
+}
+
+/// A leaf of the resource tree, with its path
+#[derive(Clone,Copy)]
+pub struct Resource<'pe,'data: 'pe> {
+	pub rtype: ResourceId<'data>,
+	pub name: ResourceId<'data>,
+	pub lang: u16,
+	pub data: ResourceData<'pe,'data>,
+}
+
+impl<'data> Pe<'data> {
+	pub fn get_resources<'pe>(&'pe self) -> Result<Resources<'pe,'data>> {
+		let ddir=try!(self.get_directory::<ResourceDirectory>());
+		if ddir.virtual_address.get()==0 {
+			return Err(Error::DirectoryMissing);
+		}
+		Ok(Resources{pe:self,base:ddir.virtual_address})
+	}
+
+	/// Get the data of a resource. See `Resources::find` for how the language
+	/// is chosen.
+	pub fn find_resource(&self, rtype: ResourceId, name: ResourceId, lang: Option<u16>) -> Result<&'data [u8]> {
+		try!(try!(self.get_resources()).find(rtype,name,lang)).data.get_data()
+	}
+}
+
+impl<'pe,'data: 'pe> Resources<'pe,'data> {
+	fn get_dir(&self, offset: u32) -> Result<ResourceDir<'pe,'data>> {
+		let rva: RVA<ResourceDirectory>=self.base.offset(offset);
+		let dir=try!(self.pe.ref_at(rva));
+		let count=dir.number_of_named_entries as u32+dir.number_of_id_entries as u32;
+		let entries=try!(self.pe.ref_slice_at(rva.offset(size_of::<ResourceDirectory>() as u32),count));
+		Ok(ResourceDir{resources:*self,dir:dir,entries:entries})
+	}
+
+	pub fn get_root(&self) -> Result<ResourceDir<'pe,'data>> {
+		self.get_dir(0)
+	}
+
+	/// All resources, in the order they appear in the tree
+	pub fn list(&self) -> Result<Vec<Resource<'pe,'data>>> {
+		let mut resources=vec![];
+		for rtype in try!(self.get_root()).entries() {
+			let names=try!(try!(rtype.get_node()).into_directory());
+			for name in names.entries() {
+				let langs=try!(try!(name.get_node()).into_directory());
+				for lang in langs.entries() {
+					let lang_id=match try!(lang.get_id()) {
+						ResourceId::Id(id) => id,
+						ResourceId::Name(_) => return Err(Error::InvalidResource),
+					};
+					resources.push(Resource{
+						rtype:try!(rtype.get_id()),
+						name:try!(name.get_id()),
+						lang:lang_id,
+						data:try!(try!(lang.get_node()).into_data()),
+					});
+				}
+			}
+		}
+		Ok(resources)
+	}
+
+	/// Find a resource by its path. If there's no resource in language
+	/// `lang`, the resource in the neutral sublanguage of the same primary
+	/// language, the default sublanguage, `LANG_NEUTRAL` and `LANG_ENGLISH_US`
+	/// are tried, in that order. If none of those exist, or `lang` is `None`
+	/// and there's no resource in `LANG_NEUTRAL` or `LANG_ENGLISH_US`, the
+	/// first language is used.
+	pub fn find(&self, rtype: ResourceId, name: ResourceId, lang: Option<u16>) -> Result<Resource<'pe,'data>> {
+		let types=try!(self.get_root());
+		let type_entry=try!(types.find(&rtype));
+		let names=try!(try!(type_entry.get_node()).into_directory());
+		let name_entry=try!(names.find(&name));
+		let langs=try!(try!(name_entry.get_node()).into_directory());
+		let mut candidates=vec![];
+		if let Some(lang)=lang {
+			let primary=lang&0x3ff;
+			candidates.extend_from_slice(&[lang,primary,primary|0x400]);
+		}
+		candidates.extend_from_slice(&[LANG_NEUTRAL,LANG_ENGLISH_US]);
+		let lang_entry=try!(candidates.iter()
+			.filter_map(|&lang|langs.find(&ResourceId::Id(lang)).ok())
+			.next()
+			.or(langs.entries().next())
+			.ok_or(Error::ResourceNotFound));
+		let lang_id=match try!(lang_entry.get_id()) {
+			ResourceId::Id(id) => id,
+			ResourceId::Name(_) => return Err(Error::InvalidResource),
+		};
+		Ok(Resource{
+			rtype:try!(type_entry.get_id()),
+			name:try!(name_entry.get_id()),
+			lang:lang_id,
+			data:try!(try!(lang_entry.get_node()).into_data()),
+		})
+	}
+}
+
+impl<'pe,'data: 'pe> ResourceDir<'pe,'data> {
+	pub fn get_directory(&self) -> &'data ResourceDirectory {
+		self.dir
+	}
+
+	/// Named entries come first, sorted by name, followed by ID entries,
+	/// sorted by ID
+	pub fn entries(&self) -> ResourceEntryIter<'pe,'data> {
+		ResourceEntryIter{resources:self.resources,entries:self.entries.iter()}
+	}
+
+	/// Returns `ResourceNotFound` if there's no entry with this ID
+	pub fn find(&self, id: &ResourceId) -> Result<ResourceEntry<'pe,'data>> {
+		for entry in self.entries() {
+			if try!(entry.get_id()).matches(id) {
+				return Ok(entry);
+			}
+		}
+		Err(Error::ResourceNotFound)
+	}
+}
+
+impl<'pe,'data: 'pe> Iterator for ResourceEntryIter<'pe,'data> {
+	type Item=ResourceEntry<'pe,'data>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let resources=self.resources;
+		self.entries.next().map(|entry|ResourceEntry{resources:resources,entry:entry})
+	}
+}
+
+impl<'pe,'data: 'pe> ResourceEntry<'pe,'data> {
+	pub fn get_entry(&self) -> &'data ResourceDirectoryEntry {
+		self.entry
+	}
+
+	pub fn get_id(&self) -> Result<ResourceId<'data>> {
+		if !self.entry.is_named() {
+			return Ok(ResourceId::Id(self.entry.name as u16));
+		}
+		let rva: RVA<u16>=self.resources.base.offset(self.entry.name&!RESOURCE_NAME_IS_STRING);
+		let len=*try!(self.resources.pe.ref_at(rva));
+		Ok(ResourceId::Name(try!(self.resources.pe.ref_slice_at(rva.offset(2),len as u32))))
+	}
+
+	pub fn get_node(&self) -> Result<ResourceNode<'pe,'data>> {
+		let offset=self.entry.offset&!RESOURCE_DATA_IS_DIRECTORY;
+		if self.entry.is_directory() {
+			Ok(ResourceNode::Directory(try!(self.resources.get_dir(offset))))
+		} else {
+			let entry=try!(self.resources.pe.ref_at(self.resources.base.offset(offset)));
+			Ok(ResourceNode::Data(ResourceData{pe:self.resources.pe,entry:entry}))
+		}
+	}
+}
+
+impl<'pe,'data: 'pe> ResourceNode<'pe,'data> {
+	/// Returns `InvalidResource` if this is a data entry
+	pub fn into_directory(self) -> Result<ResourceDir<'pe,'data>> {
+		match self {
+			ResourceNode::Directory(dir) => Ok(dir),
+			ResourceNode::Data(_) => Err(Error::InvalidResource),
+		}
+	}
+
+	/// Returns `InvalidResource` if this is a directory
+	pub fn into_data(self) -> Result<ResourceData<'pe,'data>> {
+		match self {
+			ResourceNode::Directory(_) => Err(Error::InvalidResource),
+			ResourceNode::Data(data) => Ok(data),
+		}
+	}
+}
+
+impl<'pe,'data: 'pe> ResourceData<'pe,'data> {
+	pub fn get_entry(&self) -> &'data ResourceDataEntry {
+		self.entry
+	}
+
+	pub fn get_code_page(&self) -> u32 {
+		self.entry.code_page
+	}
+
+	pub fn get_data(&self) -> Result<&'data [u8]> {
+		self.pe.ref_slice_at(self.entry.data,self.entry.size)
+	}
+}
@@ -0,0 +1,185 @@
+/*
+ * Zero-copy Portable Executable parser
+ *
+ * (C) Copyright 2016 Jethro G. Beekman
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; version 2
+ * of the License.
+ */
+
+//! String table (`RT_STRING`) and message table (`RT_MESSAGETABLE`)
+//! decoding.
+
+use std::mem::size_of;
+
+use super::{ResourceId,RT_STRING,RT_MESSAGETABLE,read_utf16z};
+use super::super::{Pe,Error,Result};
+use super::super::types::{MessageResourceData,MessageResourceBlock,MESSAGE_RESOURCE_ANSI,MESSAGE_RESOURCE_UNICODE,MESSAGE_RESOURCE_UTF8};
+use super::super::utility::{FP,URP,FPRef,read_u16};
+
+/// The number of strings in an `RT_STRING` resource
+pub const STRINGS_PER_BLOCK: usize = 16;
+
+/// The size of `Length` and `Flags` in a message entry
+const MESSAGE_ENTRY_HEADER_SIZE: usize = 4;
+
+/// Strings with ID `id` are stored in the `RT_STRING` resource with this name
+pub fn string_block_id(id: u16) -> u16 {
+	(id>>4)+1
+}
+
+/// The strings of an `RT_STRING` resource
+#[derive(Debug,Clone)]
+pub struct StringBlock {
+	/// Always `STRINGS_PER_BLOCK` strings. Missing strings are empty.
+	pub strings: Vec<String>,
+}
+
+impl StringBlock {
+	/// Parse the data of an `RT_STRING` resource. If the data ends early, the
+	/// remaining strings are empty.
+	pub fn parse(data: &[u8]) -> Result<StringBlock> {
+		let mut strings=Vec::with_capacity(STRINGS_PER_BLOCK);
+		let mut pos=0;
+		while strings.len()<STRINGS_PER_BLOCK && pos<data.len() {
+			let len=try!(read_u16(data,pos)) as usize;
+			pos+=2;
+			let mut chars=Vec::with_capacity(len);
+			for i in 0..len {
+				chars.push(try!(read_u16(data,pos+i*2)));
+			}
+			pos+=len*2;
+			strings.push(String::from_utf16_lossy(&chars));
+		}
+		strings.resize(STRINGS_PER_BLOCK,String::new());
+		Ok(StringBlock{strings:strings})
+	}
+
+	/// Get string `id` if it is in this block. Only the low 4 bits of `id`
+	/// are used. Returns `None` for empty strings, like `LoadString` does.
+	pub fn get(&self, id: u16) -> Option<&str> {
+		let string=&self.strings[id as usize%STRINGS_PER_BLOCK];
+		if string.is_empty() { None } else { Some(string) }
+	}
+}
+
+/// The messages of an `RT_MESSAGETABLE` resource
+#[derive(Debug,Clone)]
+pub struct MessageTable {
+	/// IDs and texts, in the order they appear in the resource. Texts
+	/// usually end with a line break.
+	pub messages: Vec<(u32,String)>,
+}
+
+/// Decode the text of a message entry, up to the NULL terminator. ANSI text
+/// is decoded as ISO-8859-1, since the code page is not known.
+fn message_text(data: &[u8], flags: u16) -> Result<String> {
+	match flags {
+		MESSAGE_RESOURCE_UNICODE => Ok(read_utf16z(data,0,data.len()).0),
+		MESSAGE_RESOURCE_ANSI | MESSAGE_RESOURCE_UTF8 => {
+			let data=match data.iter().position(|&b|b==0) {
+				Some(end) => &data[..end],
+				None => data,
+			};
+			if flags==MESSAGE_RESOURCE_UTF8 {
+				Ok(String::from_utf8_lossy(data).into_owned())
+			} else {
+				Ok(data.iter().map(|&b|b as char).collect())
+			}
+		},
+		_ => Err(Error::InvalidResource),
+	}
+}
+
+/// Get the message blocks of an `RT_MESSAGETABLE` resource
+fn message_blocks(data: &[u8]) -> Result<&[MessageResourceBlock]> {
+	let header=try!(data.ref_at::<MessageResourceData>(FP::new(0)));
+	let blocks=try!(data.ref_slice_at::<MessageResourceBlock>(FP::new(size_of::<MessageResourceData>() as u32),header.number_of_blocks));
+	if blocks.iter().any(|block|block.high_id<block.low_id) {
+		return Err(Error::InvalidResource);
+	}
+	Ok(blocks)
+}
+
+/// Read the message entry at `pos`. Returns the flags, the text data and the
+/// position of the next entry.
+fn message_entry(data: &[u8], pos: usize) -> Result<(u16,&[u8],usize)> {
+	let length=try!(read_u16(data,pos)) as usize;
+	let flags=try!(read_u16(data,pos+2));
+	if length<MESSAGE_ENTRY_HEADER_SIZE {
+		return Err(Error::InvalidResource);
+	}
+	let text=try!(data.get(pos+MESSAGE_ENTRY_HEADER_SIZE..pos+length).ok_or(Error::InvalidSize));
+	Ok((flags,text,pos+length))
+}
+
+/// Find message `id` in the data of an `RT_MESSAGETABLE` resource, without
+/// decoding the other messages.
+fn find_message(data: &[u8], id: u32) -> Result<Option<String>> {
+	for block in try!(message_blocks(data)) {
+		if id<block.low_id || id>block.high_id {
+			continue;
+		}
+		let mut pos=block.offset_to_entries as usize;
+		for _ in block.low_id..id {
+			pos=try!(message_entry(data,pos)).2;
+		}
+		let (flags,text,_)=try!(message_entry(data,pos));
+		return message_text(text,flags).map(Some);
+	}
+	Ok(None)
+}
+
+impl MessageTable {
+	/// Parse the data of an `RT_MESSAGETABLE` resource
+	pub fn parse(data: &[u8]) -> Result<MessageTable> {
+		let mut messages=vec![];
+		for block in try!(message_blocks(data)) {
+			let mut pos=block.offset_to_entries as usize;
+			let mut id=block.low_id;
+			loop {
+				let (flags,text,next)=try!(message_entry(data,pos));
+				messages.push((id,try!(message_text(text,flags))));
+				pos=next;
+				if id==block.high_id {
+					break;
+				}
+				id+=1;
+			}
+		}
+		Ok(MessageTable{messages:messages})
+	}
+
+	pub fn get(&self, id: u32) -> Option<&str> {
+		self.messages.iter().find(|&&(message_id,_)|message_id==id).map(|&(_,ref text)|&**text)
+	}
+}
+
+impl<'data> Pe<'data> {
+	/// Get the `RT_STRING` resource that contains string `id`. See
+	/// `Resources::find` for how the language is chosen.
+	pub fn get_string_block(&self, id: u16, lang: Option<u16>) -> Result<StringBlock> {
+		StringBlock::parse(try!(self.find_resource(RT_STRING,ResourceId::Id(string_block_id(id)),lang)))
+	}
+
+	/// Look up string `id`, like `LoadString`. Returns `ResourceNotFound` if
+	/// the string is empty.
+	pub fn get_string(&self, id: u16, lang: Option<u16>) -> Result<String> {
+		try!(self.get_string_block(id,lang)).get(id).map(|s|s.to_owned()).ok_or(Error::ResourceNotFound)
+	}
+
+	/// Look up message `id`, like `FormatMessage`. All `RT_MESSAGETABLE`
+	/// resources are searched, in order. Only the matching message is decoded.
+	pub fn get_message(&self, id: u32, lang: Option<u16>) -> Result<String> {
+		let resources=try!(self.get_resources());
+		let names=try!(try!(try!(try!(resources.get_root()).find(&RT_MESSAGETABLE)).get_node()).into_directory());
+		for name in names.entries() {
+			if let Some(text)=try!(find_message(try!(try!(resources.find(RT_MESSAGETABLE,try!(name.get_id()),lang)).data.get_data()),id)) {
+				return Ok(text);
+			}
+		}
+		Err(Error::ResourceNotFound)
+	}
+}
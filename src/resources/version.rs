@@ -0,0 +1,201 @@
+/*
+ * Zero-copy Portable Executable parser
+ *
+ * (C) Copyright 2016 Jethro G. Beekman
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; version 2
+ * of the License.
+ */
+
+//! Version information (`VS_VERSIONINFO`) decoding.
+
+use std::cmp::min;
+use std::mem::size_of;
+
+use super::{read_utf16z,align4,RT_VERSION};
+use super::super::{Pe,Error,Result};
+use super::super::types::{FixedFileInfo,FIXED_FILE_INFO_SIGNATURE};
+use super::super::types::file_flags::FileFlags;
+use super::super::utility::{FP,URP,FPRef,read_u16};
+
+/// The size of `wLength`, `wValueLength` and `wType`
+const BLOCK_HEADER_SIZE: usize = 6;
+
+#[derive(Debug,Clone)]
+pub struct VersionInfo {
+	/// `None` if the fixed file info is missing or has the wrong signature
+	pub fixed: Option<FixedFileInfo>,
+	pub string_tables: Vec<StringTable>,
+	/// Language and code page pairs from `VarFileInfo\Translation`
+	pub translations: Vec<(u16,u16)>,
+}
+
+/// A `StringFileInfo` table for one language and code page
+#[derive(Debug,Clone)]
+pub struct StringTable {
+	/// Eight hexadecimal digits: the language, followed by the code page
+	pub key: String,
+	/// Names and values, such as `CompanyName` and `FileDescription`
+	pub strings: Vec<(String,String)>,
+}
+
+/// A node in the version information tree
+struct Block {
+	key: String,
+	text: bool,
+	value_start: usize,
+	value_end: usize,
+	children_start: usize,
+	end: usize,
+}
+
+/// Parse the block at `pos`. Lengths that extend past `end` are truncated.
+fn parse_block(data: &[u8], pos: usize, end: usize) -> Result<Block> {
+	let length=try!(read_u16(data,pos)) as usize;
+	let value_length=try!(read_u16(data,pos+2)) as usize;
+	let value_type=try!(read_u16(data,pos+4));
+	if length<BLOCK_HEADER_SIZE {
+		return Err(Error::InvalidResource);
+	}
+	let end=min(pos+length,end);
+	let (key,key_end)=read_utf16z(data,pos+BLOCK_HEADER_SIZE,end);
+	let value_start=min(align4(key_end),end);
+	// Text values are measured in characters
+	let value_size=if value_type==1 { value_length*2 } else { value_length };
+	let value_end=min(value_start+value_size,end);
+	Ok(Block{
+		key:key,
+		text:value_type==1,
+		value_start:value_start,
+		value_end:value_end,
+		children_start:min(align4(value_end),end),
+		end:end,
+	})
+}
+
+fn children(data: &[u8], block: &Block) -> Result<Vec<Block>> {
+	let mut children=vec![];
+	let mut pos=block.children_start;
+	while pos+BLOCK_HEADER_SIZE<=block.end {
+		// Trailing padding
+		if try!(read_u16(data,pos))==0 {
+			break;
+		}
+		let child=try!(parse_block(data,pos,block.end));
+		pos=align4(child.end);
+		children.push(child);
+	}
+	Ok(children)
+}
+
+/// Text values are read up to the NULL terminator, since some compilers
+/// store the value length in bytes instead of characters
+fn text_value(data: &[u8], block: &Block) -> String {
+	if block.value_start==block.value_end {
+		return String::new();
+	}
+	read_utf16z(data,block.value_start,block.end).0
+}
+
+fn split_version(ms: u32, ls: u32) -> [u16;4] {
+	[(ms>>16) as u16,ms as u16,(ls>>16) as u16,ls as u16]
+}
+
+impl VersionInfo {
+	/// Parse the data of an `RT_VERSION` resource
+	pub fn parse(data: &[u8]) -> Result<VersionInfo> {
+		let root=try!(parse_block(data,0,data.len()));
+		if root.key!="VS_VERSION_INFO" {
+			return Err(Error::InvalidResource);
+		}
+		let value=&data[root.value_start..root.value_end];
+		let fixed=if value.len()>=size_of::<FixedFileInfo>() {
+			let fixed=try!(value.ref_at::<FixedFileInfo>(FP::new(0)));
+			if fixed.signature==FIXED_FILE_INFO_SIGNATURE { Some(fixed.clone()) } else { None }
+		} else {
+			None
+		};
+
+		let mut info=VersionInfo{fixed:fixed,string_tables:vec![],translations:vec![]};
+		for child in try!(children(data,&root)) {
+			match &*child.key {
+				"StringFileInfo" => for table in try!(children(data,&child)) {
+					let mut strings=vec![];
+					for string in try!(children(data,&table)) {
+						let value=if string.text {
+							text_value(data,&string)
+						} else {
+							String::from_utf8_lossy(&data[string.value_start..string.value_end]).into_owned()
+						};
+						strings.push((string.key,value));
+					}
+					info.string_tables.push(StringTable{key:table.key,strings:strings});
+				},
+				"VarFileInfo" => for var in try!(children(data,&child)) {
+					if var.key=="Translation" {
+						let mut pos=var.value_start;
+						while pos+4<=var.value_end {
+							info.translations.push((try!(read_u16(data,pos)),try!(read_u16(data,pos+2))));
+							pos+=4;
+						}
+					}
+				},
+				_ => {},
+			}
+		}
+		Ok(info)
+	}
+
+	pub fn get_file_version(&self) -> Option<[u16;4]> {
+		self.fixed.as_ref().map(|fixed|split_version(fixed.file_version_ms,fixed.file_version_ls))
+	}
+
+	pub fn get_product_version(&self) -> Option<[u16;4]> {
+		self.fixed.as_ref().map(|fixed|split_version(fixed.product_version_ms,fixed.product_version_ls))
+	}
+
+	/// The file flags, masked by the valid flags mask
+	pub fn get_file_flags(&self) -> Option<FileFlags> {
+		self.fixed.as_ref().map(|fixed|FileFlags::from_bits_truncate(fixed.file_flags&fixed.file_flags_mask))
+	}
+
+	/// Find a string in the first string table that contains it
+	pub fn get_string(&self, name: &str) -> Option<&str> {
+		self.string_tables.iter().filter_map(|table|table.get(name)).next()
+	}
+}
+
+impl StringTable {
+	fn parse_key(&self) -> Option<(u16,u16)> {
+		if self.key.len()!=8 || !self.key.chars().all(|c|c.is_digit(16)) {
+			return None;
+		}
+		let lang=u16::from_str_radix(&self.key[..4],16).unwrap();
+		let code_page=u16::from_str_radix(&self.key[4..],16).unwrap();
+		Some((lang,code_page))
+	}
+
+	pub fn get_language(&self) -> Option<u16> {
+		self.parse_key().map(|(lang,_)|lang)
+	}
+
+	pub fn get_code_page(&self) -> Option<u16> {
+		self.parse_key().map(|(_,code_page)|code_page)
+	}
+
+	pub fn get(&self, name: &str) -> Option<&str> {
+		self.strings.iter().find(|&&(ref key,_)|key==name).map(|&(_,ref value)|&**value)
+	}
+}
+
+impl<'data> Pe<'data> {
+	/// Decode the first `RT_VERSION` resource
+	pub fn get_version_info(&self) -> Result<VersionInfo> {
+		let resources=try!(self.get_resources());
+		let names=try!(try!(try!(try!(resources.get_root()).find(&RT_VERSION)).get_node()).into_directory());
+		let name=try!(try!(names.entries().next().ok_or(Error::ResourceNotFound)).get_id());
+		VersionInfo::parse(try!(try!(resources.find(RT_VERSION,name,None)).data.get_data()))
+	}
+}
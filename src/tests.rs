@@ -14,6 +14,9 @@ extern crate itertools;
 use super::*;
 use std::io::Read;
 use std::fs::File;
+use std::mem::size_of;
+use std::ffi::OsStr;
+use utility::{read_u16,read_u32,write_u16,write_u32,push_u16,push_u32};
 
 // Testing on SQLite binaries since those are in the public domain
 lazy_static! {
@@ -231,3 +234,1554 @@ fn list_exports() {
 
 	assert_eq!(&sqlite_x64_exports[0],edir.lookup_symbol("sqlite3_aggregate_context").unwrap());
 }
+
+#[test]
+fn lookup_export_symbol() {
+	for pe in [&*SQLITE_X86_PE,&*SQLITE_X64_PE].iter() {
+		let edir=pe.get_exports().unwrap();
+		let addresses=edir.get_export_addresses().unwrap();
+		for (&name,&offset) in edir.get_names().unwrap().iter().zip(edir.get_ordinal_offsets().unwrap()) {
+			let name=pe.ref_cstr_at(name).unwrap().as_os_str().to_str().unwrap();
+			assert_eq!(edir.lookup_symbol(name).unwrap(),edir.concretize_export_address(&addresses[offset as usize]));
+		}
+		assert!(edir.lookup_symbol("sqlite3_").is_err());
+		assert!(edir.lookup_symbol("sqlite3_zzz").is_err());
+		assert!(edir.lookup_symbol("").is_err());
+	}
+
+	// Unsorted name tables still work
+	let edir=SQLITE_X86_PE.get_exports().unwrap();
+	let names=edir.get_names().unwrap();
+	let (fp0,fp1)=(fp_of(&SQLITE_X86_BUF,&names[0]),fp_of(&SQLITE_X86_BUF,&names[1]));
+	let mut buf=SQLITE_X86_BUF.clone();
+	let (name0,name1)=(read_u32(&buf,fp0).unwrap(),read_u32(&buf,fp1).unwrap());
+	write_u32(&mut buf,fp0,name1);
+	write_u32(&mut buf,fp1,name0);
+	let pe=Pe::new(&buf).unwrap();
+	let edir=pe.get_exports().unwrap();
+	let addresses=edir.get_export_addresses().unwrap();
+	assert_eq!(edir.lookup_symbol("sqlite3_aggregate_count").unwrap(),edir.concretize_export_address(&addresses[0]));
+	assert_eq!(edir.lookup_symbol("sqlite3_aggregate_context").unwrap(),edir.concretize_export_address(&addresses[1]));
+	assert_eq!(edir.lookup_symbol("sqlite3_win32_write_debug").unwrap(),edir.concretize_export_address(&addresses[230]));
+}
+
+#[test]
+fn lookup_export_ordinal() {
+	let edir=SQLITE_X86_PE.get_exports().unwrap();
+	let base=edir.get_export_directory().ordinal_base;
+	let count=edir.get_export_directory().address_table_entries;
+	assert_eq!(edir.lookup_ordinal(base).unwrap(),edir.lookup_symbol("sqlite3_aggregate_context").unwrap());
+	assert_eq!(edir.lookup_ordinal(base+230).unwrap(),edir.lookup_symbol("sqlite3_win32_write_debug").unwrap());
+	assert!(edir.lookup_ordinal(base+count).is_err());
+	assert!(base==0 || edir.lookup_ordinal(base-1).is_err());
+
+	// Empty slots are not exports
+	let mut buf=SQLITE_X86_BUF.clone();
+	let eat_fp=fp_of(&SQLITE_X86_BUF,&edir.get_export_addresses().unwrap()[5]);
+	write_u32(&mut buf,eat_fp,0);
+	let pe=Pe::new(&buf).unwrap();
+	let edir=pe.get_exports().unwrap();
+	assert!(edir.lookup_ordinal(base+5).is_err());
+	assert!(edir.lookup_ordinal(base+6).is_ok());
+	assert_eq!(edir.iter().unwrap().count(),count as usize-1);
+}
+
+#[test]
+fn list_imports() {
+	let sqlite_imports=[
+		"api-ms-win-core-file-l1-2-1.dll",         "api-ms-win-core-profile-l1-1-0.dll",
+		"api-ms-win-core-sysinfo-l1-2-1.dll",      "api-ms-win-core-memory-l1-1-2.dll",
+		"api-ms-win-core-string-l1-1-0.dll",       "api-ms-win-core-libraryloader-l1-2-0.dll",
+		"api-ms-win-core-timezone-l1-1-0.dll",     "api-ms-win-core-heap-l1-2-0.dll",
+		"api-ms-win-core-processthreads-l1-1-2.dll","api-ms-win-core-handle-l1-1-0.dll",
+		"api-ms-win-core-synch-l1-2-0.dll",        "api-ms-win-core-debug-l1-1-1.dll",
+		"api-ms-win-core-errorhandling-l1-1-1.dll","api-ms-win-core-localization-l1-2-1.dll",
+		"api-ms-win-core-libraryloader-l2-1-0.dll","api-ms-win-core-file-l2-1-1.dll",
+		"VCRUNTIME140_APP.dll",                    "api-ms-win-crt-heap-l1-1-0.dll",
+		"api-ms-win-crt-utility-l1-1-0.dll",       "api-ms-win-crt-string-l1-1-0.dll",
+		"api-ms-win-crt-time-l1-1-0.dll",          "api-ms-win-crt-math-l1-1-0.dll",
+		"api-ms-win-crt-runtime-l1-1-0.dll",       "api-ms-win-core-interlocked-l1-2-0.dll",
+		"api-ms-win-core-winrt-l1-1-0.dll",
+	];
+	let sqlite_x86_file_imports=[
+		(30,"FlushFileBuffers"), (69,"LockFileEx"),   (71,"ReadFile"),         (41,"GetFileAttributesExW"),
+		(89,"WriteFile"),        (9,"DeleteFileW"),   (76,"SetEndOfFile"),     (88,"UnlockFileEx"),
+		(84,"SetFilePointerEx"), (4,"CreateFile2"),
+	];
+	let sqlite_x64_file_imports=[
+		(30,"FlushFileBuffers"), (88,"UnlockFileEx"), (71,"ReadFile"),         (69,"LockFileEx"),
+		(9,"DeleteFileW"),       (89,"WriteFile"),    (76,"SetEndOfFile"),     (84,"SetFilePointerEx"),
+		(41,"GetFileAttributesExW"), (4,"CreateFile2"),
+	];
+
+	for &(pe,file_imports) in &[(&*SQLITE_X86_PE,&sqlite_x86_file_imports),(&*SQLITE_X64_PE,&sqlite_x64_file_imports)] {
+		let imports: Vec<_>=pe.get_imports().unwrap().map(Result::unwrap).collect();
+		itertools::assert_equal(imports.iter().map(|import|import.get_name().as_os_str()),sqlite_imports.iter().cloned());
+		let lookups=imports[0].get_lookup_table().map(|lookup|match lookup.unwrap() {
+			ImportLookup::HintName(hint,name) => (hint,name.as_os_str()),
+			ImportLookup::Ordinal(_) => panic!("Unexpected import by ordinal"),
+		});
+		itertools::assert_equal(lookups,file_imports.iter().map(|&(hint,name)|(hint,name.as_ref())));
+	}
+}
+
+fn fp_of<T>(buf: &[u8], r: &T) -> usize {
+	(r as *const _ as usize)-(buf.as_ptr() as usize)
+}
+
+#[test]
+fn list_delay_imports() {
+	assert_eq!(SQLITE_X86_PE.get_delay_imports().unwrap().count(),0);
+
+	// Turn the first regular import into a delay import, in both the modern
+	// RVA-based form and the legacy VA-based form
+	let pe=&*SQLITE_X86_PE;
+	let image_base=pe.get_optional_header().get_image_base() as u32;
+	let desc_rva=pe.get_directory::<ImportDescriptor>().unwrap().virtual_address;
+	let desc_fp=pe.resolve_rva(desc_rva).unwrap().get() as usize;
+	let ddir_fp=fp_of(&SQLITE_X86_BUF,pe.get_directory_raw(DirectoryEntry::DelayImportDescriptor).unwrap());
+	let import=pe.get_imports().unwrap().next().unwrap().unwrap();
+	let desc=import.get_descriptor();
+	let ilt_fp=pe.resolve_rva(desc.import_lookup_table).unwrap().get() as usize;
+	let num_thunks=import.get_lookup_table().count();
+
+	for &bias in &[0,image_base] {
+		let mut buf=SQLITE_X86_BUF.clone();
+		let fields=[if bias==0 { DELAY_IMPORT_ATTRIBUTE_RVA } else { 0 },desc.name.get()+bias,0,
+			desc.import_address_table.get()+bias,desc.import_lookup_table.get()+bias,0,0,0];
+		for (i,&field) in fields.iter().chain([0;8].iter()).enumerate() {
+			write_u32(&mut buf,desc_fp+i*4,field);
+		}
+		for i in 0..num_thunks {
+			let thunk=ilt_fp+i*4;
+			let value=read_u32(&buf,thunk).unwrap();
+			write_u32(&mut buf,thunk,value+bias);
+		}
+		write_u32(&mut buf,ddir_fp,desc_rva.get());
+		write_u32(&mut buf,ddir_fp+4,(size_of::<DelayImportDescriptor>()*2) as u32);
+
+		let delay_pe=Pe::new(&buf).unwrap();
+		let delay_imports: Vec<_>=delay_pe.get_delay_imports().unwrap().map(Result::unwrap).collect();
+		assert_eq!(delay_imports.len(),1);
+		let delay_import=&delay_imports[0];
+		assert_eq!(delay_import.get_descriptor().is_rva_based(),bias==0);
+		assert_eq!(delay_import.get_name().as_os_str(),"api-ms-win-core-file-l1-2-1.dll");
+		assert_eq!(delay_import.get_address_table(),desc.import_address_table);
+		assert_eq!(delay_import.get_name_table(),desc.import_lookup_table);
+		assert_eq!(delay_import.get_module_handle().get(),0);
+		let names=delay_import.get_lookup_table().map(|lookup|match lookup.unwrap() {
+			ImportLookup::HintName(_,name) => name.as_os_str(),
+			ImportLookup::Ordinal(_) => panic!("Unexpected import by ordinal"),
+		});
+		itertools::assert_equal(names,import.get_lookup_table().map(|lookup|match lookup.unwrap() {
+			ImportLookup::HintName(_,name) => name.as_os_str(),
+			ImportLookup::Ordinal(_) => panic!("Unexpected import by ordinal"),
+		}));
+
+		// Without a name table, there is nothing to iterate over
+		let mut buf=buf.clone();
+		write_u32(&mut buf,desc_fp+16,0);
+		let delay_pe=Pe::new(&buf).unwrap();
+		let delay_import=delay_pe.get_delay_imports().unwrap().next().unwrap().unwrap();
+		assert_eq!(delay_import.get_name_table().get(),0);
+		assert_eq!(delay_import.get_lookup_table().count(),0);
+	}
+}
+
+#[test]
+fn list_bound_imports() {
+	assert_eq!(SQLITE_X86_PE.get_bound_imports().unwrap().count(),0);
+
+	// Linkers put the bound import directory in the slack space after the
+	// section headers
+	let pe=&*SQLITE_X86_PE;
+	let sections=pe.get_sections();
+	let base=fp_of(&SQLITE_X86_BUF,&sections[sections.len()-1])+size_of::<SectionHeader>();
+	let ddir_fp=fp_of(&SQLITE_X86_BUF,pe.get_directory_raw(DirectoryEntry::BoundImport).unwrap());
+	let mut buf=SQLITE_X86_BUF.clone();
+	let names=b"KERNEL32.dll\0NTDLL.DLL\0USER32.dll\0";
+	let entries=[
+		(0x11111111,32,1),
+		(0x22222222,45,0),
+		(0x33333333,55,0),
+		(0,0,0),
+	];
+	for (i,&(time_date_stamp,offset_module_name,number)) in entries.iter().enumerate() {
+		write_u32(&mut buf,base+i*8,time_date_stamp);
+		write_u32(&mut buf,base+i*8+4,offset_module_name|(number<<16));
+	}
+	buf[base+32..base+32+names.len()].copy_from_slice(names);
+	write_u32(&mut buf,ddir_fp,base as u32);
+	write_u32(&mut buf,ddir_fp+4,(32+names.len()) as u32);
+
+	let bound_pe=Pe::new(&buf).unwrap();
+	let bound_imports: Vec<_>=bound_pe.get_bound_imports().unwrap().map(Result::unwrap).collect();
+	assert_eq!(bound_imports.len(),2);
+	assert_eq!(bound_imports[0].get_name().as_os_str(),"KERNEL32.dll");
+	assert_eq!({bound_imports[0].get_descriptor().time_date_stamp},0x11111111);
+	assert_eq!(bound_imports[0].get_forwarders().len(),1);
+	let forwarder=&bound_imports[0].get_forwarders()[0];
+	assert_eq!({forwarder.time_date_stamp},0x22222222);
+	assert_eq!(bound_imports[0].get_forwarder_name(forwarder).unwrap().as_os_str(),"NTDLL.DLL");
+	assert_eq!(bound_imports[1].get_name().as_os_str(),"USER32.dll");
+	assert!(bound_imports[1].get_forwarders().is_empty());
+	assert!(!bound_imports[1].is_bound_to(&SQLITE_X86_PE));
+}
+
+#[test]
+fn lookup_import_address() {
+	fn lookup_name<'a>(map: &'a ImportAddressMap, rva: u32) -> Option<(&'a OsStr,&'a OsStr)> {
+		map.lookup(RVA::new(rva)).map(|slot|match slot.import {
+			ImportLookup::HintName(_,name) => (slot.dll.as_os_str(),name.as_os_str()),
+			ImportLookup::Ordinal(_) => panic!("Unexpected import by ordinal"),
+		})
+	}
+
+	let map=SQLITE_X86_PE.get_import_address_map().unwrap();
+	let file=OsStr::new("api-ms-win-core-file-l1-2-1.dll");
+	let vcruntime=OsStr::new("VCRUNTIME140_APP.dll");
+	assert_eq!(map.get_slots().len(),74);
+	assert_eq!(lookup_name(&map,0x111000),Some((vcruntime,OsStr::new("__telemetry_main_invoke_trigger"))));
+	assert_eq!(lookup_name(&map,0x1110ac),Some((file,OsStr::new("FlushFileBuffers"))));
+	assert_eq!(lookup_name(&map,0x1110ae),Some((file,OsStr::new("FlushFileBuffers"))));
+	assert_eq!(lookup_name(&map,0x1110b0),Some((file,OsStr::new("LockFileEx"))));
+	// NULL terminator
+	assert_eq!(lookup_name(&map,0x1110d4),None);
+	assert_eq!(lookup_name(&map,0x110fff),None);
+
+	let map=SQLITE_X64_PE.get_import_address_map().unwrap();
+	let iat=SQLITE_X64_PE.get_imports().unwrap().next().unwrap().unwrap().get_descriptor().import_address_table;
+	assert_eq!(lookup_name(&map,iat.get()+8),Some((file,OsStr::new("UnlockFileEx"))));
+	assert_eq!(lookup_name(&map,iat.get()+15),Some((file,OsStr::new("UnlockFileEx"))));
+}
+
+#[test]
+fn imphash() {
+	assert_eq!(SQLITE_X86_PE.imphash().unwrap(),"45b8490e43b8d10268e353039c9c2c52");
+	assert_eq!(SQLITE_X64_PE.imphash().unwrap(),"261731365cfe54667d234f116feae621");
+}
+
+#[test]
+fn import_ordinal_names() {
+	fn cstr(s: &[u8]) -> &[CChar] {
+		unsafe{::std::mem::transmute(s)}
+	}
+
+	assert_eq!(ordinals::lookup("WS2_32.dll",115),Some("WSAStartup"));
+	assert_eq!(ordinals::lookup("oleaut32.dll",2),Some("SysAllocString"));
+	assert_eq!(ordinals::lookup("comctl32.dll",17),Some("InitCommonControls"));
+	assert_eq!(ordinals::lookup_pefile("comctl32.dll",17),None);
+	assert_eq!(ordinals::lookup("kernel32.dll",1),None);
+
+	let lookup=ImportLookup::Ordinal(23);
+	assert_eq!(lookup.get_symbol_name(cstr(b"WSOCK32.DLL\0")),Some(OsStr::new("socket")));
+	assert_eq!(lookup.get_display_name(cstr(b"WSOCK32.DLL\0")),"socket");
+	assert_eq!(lookup.get_symbol_name(cstr(b"foo.dll\0")),None);
+	assert_eq!(lookup.get_display_name(cstr(b"foo.dll\0")),"#23");
+	let lookup=ImportLookup::HintName(0,cstr(b"Sleep\0"));
+	assert_eq!(lookup.get_display_name(cstr(b"kernel32.dll\0")),"Sleep");
+}
+
+#[test]
+fn iterate_exports() {
+	for pe in [&*SQLITE_X86_PE,&*SQLITE_X64_PE].iter() {
+		let edir=pe.get_exports().unwrap();
+		let base=edir.get_export_directory().ordinal_base;
+		let names=edir.get_names().unwrap();
+		let addresses=edir.get_export_addresses().unwrap();
+		let exports: Vec<_>=edir.iter().unwrap().collect();
+		assert_eq!(exports.len(),addresses.len());
+		for (i,export) in exports.iter().enumerate() {
+			assert_eq!(export.ordinal,base+i as u32);
+			itertools::assert_equal(export.names.iter().map(|name|name.as_os_str()),Some(pe.ref_cstr_at(names[i]).unwrap().as_os_str()));
+			assert_eq!(export.address,edir.concretize_export_address(&addresses[i]));
+		}
+	}
+}
+
+#[test]
+fn parse_forwarders() {
+	fn parse(s: &[u8]) -> Result<Forwarder> {
+		Forwarder::parse(unsafe{::std::mem::transmute(s)})
+	}
+
+	let fwd=parse(b"NTDLL.RtlAllocateHeap\0").unwrap();
+	assert_eq!(fwd.module.as_os_str(),"NTDLL");
+	assert_eq!(fwd.get_dll_name(),"NTDLL.dll");
+	match fwd.symbol {
+		ForwarderSymbol::Name(name) => assert_eq!(name.as_os_str(),"RtlAllocateHeap"),
+		ForwarderSymbol::Ordinal(_) => panic!("expected name"),
+	}
+
+	let fwd=parse(b"api-ms-win-core-x.dll.#12").unwrap();
+	assert_eq!(fwd.module.as_os_str(),"api-ms-win-core-x.dll");
+	assert_eq!(fwd.get_dll_name(),"api-ms-win-core-x.dll");
+	match fwd.symbol {
+		ForwarderSymbol::Ordinal(ordinal) => assert_eq!(ordinal,12),
+		ForwarderSymbol::Name(_) => panic!("expected ordinal"),
+	}
+
+	for invalid in [&b"NTDLL\0"[..],b".RtlAllocateHeap\0",b"NTDLL.\0",b"NTDLL.#\0",b"NTDLL.#12a\0"].iter() {
+		match parse(invalid) {
+			Err(Error::InvalidForwarder) => {},
+			other => panic!("unexpected result: {:?}",other),
+		}
+	}
+}
+
+fn write_cstr(buf: &mut [u8], fp: usize, s: &str) {
+	buf[fp..fp+s.len()].copy_from_slice(s.as_bytes());
+	buf[fp+s.len()]=0;
+}
+
+#[test]
+fn resolve_dependencies() {
+	use dependencies::*;
+
+	// Make the root import sqlite3_changes from sqlite3.dll and two functions
+	// from x.dll
+	let mut root_buf=SQLITE_X86_BUF.clone();
+	for import in SQLITE_X86_PE.get_imports().unwrap().map(Result::unwrap) {
+		let name=import.get_name();
+		let new_name=match &*name.as_os_str().to_string_lossy() {
+			"api-ms-win-core-profile-l1-1-0.dll" => "sqlite3.dll",
+			"api-ms-win-core-string-l1-1-0.dll" => "x.dll",
+			_ => continue,
+		};
+		write_cstr(&mut root_buf,fp_of(&SQLITE_X86_BUF,&name[0]),new_name);
+		if new_name=="sqlite3.dll" {
+			match import.get_lookup_table().next().unwrap().unwrap() {
+				ImportLookup::HintName(_,name) => write_cstr(&mut root_buf,fp_of(&SQLITE_X86_BUF,&name[0]),"sqlite3_changes"),
+				ImportLookup::Ordinal(_) => unreachable!(),
+			}
+		}
+	}
+	let root=Pe::new(&root_buf).unwrap();
+
+	// Make sqlite3.dll forward sqlite3_changes to sqlite3_close_v2 in x.dll
+	let mut sqlite3_buf=SQLITE_X64_BUF.clone();
+	let edir=SQLITE_X64_PE.get_exports().unwrap();
+	let dll_name=edir.get_export_directory().name;
+	let forwarder=format!("x.#{}",edir.get_export_directory().ordinal_base+35);
+	write_cstr(&mut sqlite3_buf,fp_of(&SQLITE_X64_BUF,&SQLITE_X64_PE.ref_cstr_at(dll_name).unwrap()[0]),&forwarder);
+	write_u32(&mut sqlite3_buf,fp_of(&SQLITE_X64_BUF,&edir.get_export_addresses().unwrap()[32]),dll_name.get());
+	let sqlite3=Pe::new(&sqlite3_buf).unwrap();
+
+	let mut resolver=Resolver::new();
+	resolver.add_module("SQLite3.dll",&sqlite3);
+	resolver.add_module("x.dll",&SQLITE_X86_PE);
+	let graph=resolver.resolve("root.exe",&root).unwrap();
+
+	assert_eq!(graph.modules,["root.exe","sqlite3.dll","x.dll"]);
+	assert!(graph.dependencies.contains(&Dependency{importer:"root.exe".into(),module:"sqlite3.dll".into(),kind:DependencyKind::Import}));
+	assert!(graph.dependencies.contains(&Dependency{importer:"root.exe".into(),module:"x.dll".into(),kind:DependencyKind::Import}));
+	assert!(graph.dependencies.contains(&Dependency{importer:"sqlite3.dll".into(),module:"x.dll".into(),kind:DependencyKind::Forwarder}));
+	assert!(graph.dependencies.contains(&Dependency{importer:"x.dll".into(),module:"vcruntime140_app.dll".into(),kind:DependencyKind::Import}));
+
+	let mut missing_modules=graph.missing_modules.clone();
+	missing_modules.sort();
+	assert_eq!(missing_modules.len(),25);
+	assert!(missing_modules.binary_search(&"api-ms-win-core-file-l1-2-1.dll".to_owned()).is_ok());
+	assert!(missing_modules.binary_search(&"api-ms-win-core-string-l1-1-0.dll".to_owned()).is_ok());
+	assert!(missing_modules.binary_search(&"api-ms-win-core-profile-l1-1-0.dll".to_owned()).is_ok());
+
+	assert_eq!(graph.missing_symbols,[
+		MissingSymbol{importer:"root.exe".into(),module:"x.dll".into(),symbol:Symbol::Name("MultiByteToWideChar".into()),reason:MissingReason::NotExported},
+		MissingSymbol{importer:"root.exe".into(),module:"x.dll".into(),symbol:Symbol::Name("WideCharToMultiByte".into()),reason:MissingReason::NotExported},
+	]);
+
+	// Make sqlite3.dll forward sqlite3_changes to itself, and break the name
+	// table of x.dll
+	let mut loop_buf=SQLITE_X64_BUF.clone();
+	let forwarder=format!("sqlite3.#{}",edir.get_export_directory().ordinal_base+32);
+	write_cstr(&mut loop_buf,fp_of(&SQLITE_X64_BUF,&SQLITE_X64_PE.ref_cstr_at(dll_name).unwrap()[0]),&forwarder);
+	write_u32(&mut loop_buf,fp_of(&SQLITE_X64_BUF,&edir.get_export_addresses().unwrap()[32]),dll_name.get());
+	let looping=Pe::new(&loop_buf).unwrap();
+	let mut x_buf=SQLITE_X86_BUF.clone();
+	let x_edir=SQLITE_X86_PE.get_exports().unwrap();
+	write_u32(&mut x_buf,fp_of(&SQLITE_X86_BUF,&x_edir.get_export_directory().name_pointer),0xfffffff0);
+	let x=Pe::new(&x_buf).unwrap();
+
+	let mut resolver=Resolver::new();
+	resolver.add_module("sqlite3.dll",&looping);
+	resolver.add_module("x.dll",&x);
+	let graph=resolver.resolve("root.exe",&root).unwrap();
+	assert_eq!(graph.missing_symbols.iter().map(|missing|(&*missing.module,missing.reason)).collect::<Vec<_>>(),[
+		("sqlite3.dll",MissingReason::ForwarderDepthExceeded),
+		("x.dll",MissingReason::MalformedExports),
+		("x.dll",MissingReason::MalformedExports),
+	]);
+}
+
+/// Build an API set schema with the given sets of `(name,[(importer,host)])`
+fn build_api_set_schema(version: u32, sets: &[(&str,&[(&str,&str)])]) -> Vec<u8> {
+	fn push_str(buf: &mut Vec<u8>, s: &str) -> (u32,u32) {
+		let offset=buf.len() as u32;
+		for c in s.encode_utf16() {
+			push_u16(buf,c);
+		}
+		(offset,buf.len() as u32-offset)
+	}
+
+	let (header_size,entry_size,value_size)=match version {
+		2 => (8,12,16),
+		4 => (16,24,20),
+		6 => (28,24,20),
+		_ => unreachable!(),
+	};
+	let mut buf=vec![0u8;header_size+entry_size*sets.len()];
+	write_u32(&mut buf,0,version);
+	match version {
+		2 => write_u32(&mut buf,4,sets.len() as u32),
+		4 => write_u32(&mut buf,12,sets.len() as u32),
+		_ => {
+			write_u32(&mut buf,12,sets.len() as u32);
+			write_u32(&mut buf,16,header_size as u32);
+		},
+	}
+	for (i,&(name,hosts)) in sets.iter().enumerate() {
+		let entry=header_size+i*entry_size;
+		let (name_offset,name_length)=push_str(&mut buf,name);
+		let values_offset=buf.len() as u32;
+		match version {
+			2 => push_u32(&mut buf,hosts.len() as u32),
+			4 => {
+				push_u32(&mut buf,0);
+				push_u32(&mut buf,hosts.len() as u32);
+			},
+			_ => {},
+		}
+		let first_value=buf.len();
+		buf.resize(first_value+value_size*hosts.len(),0);
+		for (j,&(importer,host)) in hosts.iter().enumerate() {
+			let value=first_value+j*value_size+value_size-16;
+			let (importer_offset,importer_length)=if importer.is_empty() { (0,0) } else { push_str(&mut buf,importer) };
+			let (host_offset,host_length)=push_str(&mut buf,host);
+			for (k,&field) in [importer_offset,importer_length,host_offset,host_length].iter().enumerate() {
+				write_u32(&mut buf,value+k*4,field);
+			}
+		}
+		let fields=match version {
+			2 => vec![name_offset,name_length,values_offset],
+			4 => vec![0,name_offset,name_length,name_offset,name_length,values_offset],
+			_ => vec![0,name_offset,name_length,name.rfind('-').unwrap() as u32*2,values_offset,hosts.len() as u32],
+		};
+		for (k,&field) in fields.iter().enumerate() {
+			write_u32(&mut buf,entry+k*4,field);
+		}
+	}
+	buf
+}
+
+#[test]
+fn api_set_schema() {
+	use apiset::ApiSetSchema;
+
+	let file_hosts: &[(&str,&str)]=&[("","kernelbase.dll"),("kernel32.dll","kernel32.dll")];
+	for &(version,file,winrt) in &[(2,"MS-Win-Core-File-L1-2-1","MS-Win-Core-WinRT-L1-1-0"),(4,"ms-win-core-file-l1-2-1","ms-win-core-winrt-l1-1-0"),(6,"api-ms-win-core-file-l1-2-3","api-ms-win-core-winrt-l1-1-0")] {
+		let buf=build_api_set_schema(version,&[(file,file_hosts),(winrt,&[])]);
+		let schema=ApiSetSchema::parse(&buf).unwrap();
+		assert_eq!(schema.get_version(),version);
+		itertools::assert_equal(schema.get_api_sets().iter().map(|set|&*set.name),vec![file,winrt]);
+		assert_eq!(schema.get_api_sets()[0].hosts.len(),2);
+
+		assert_eq!(schema.resolve("api-ms-win-core-file-l1-2-1.dll",None),Some("kernelbase.dll"));
+		assert_eq!(schema.resolve("API-MS-WIN-CORE-FILE-L1-2-1",Some("sqlite3.dll")),Some("kernelbase.dll"));
+		assert_eq!(schema.resolve("api-ms-win-core-file-l1-2-1.dll",Some("KERNEL32.DLL")),Some("kernel32.dll"));
+		assert!(schema.find("api-ms-win-core-winrt-l1-1-0.dll").is_some());
+		assert_eq!(schema.resolve("api-ms-win-core-winrt-l1-1-0.dll",None),None);
+		assert_eq!(schema.resolve("kernel32.dll",None),None);
+		// Only version 6 ignores the last version number
+		assert_eq!(schema.find("api-ms-win-core-file-l1-2-0.dll").is_some(),version==6);
+	}
+
+	let mut buf=build_api_set_schema(6,&[]);
+	write_u32(&mut buf,0,3);
+	match ApiSetSchema::parse(&buf) {
+		Err(Error::UnsupportedVersion) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+}
+
+#[test]
+fn resolve_dependencies_api_sets() {
+	use dependencies::*;
+	use apiset::ApiSetSchema;
+
+	let buf=build_api_set_schema(6,&[
+		("api-ms-win-core-file-l1-2-3",&[("","kernelbase.dll")]),
+		("api-ms-win-core-winrt-l1-1-0",&[]),
+	]);
+	let mut resolver=Resolver::new();
+	resolver.set_api_set_schema(ApiSetSchema::parse(&buf).unwrap());
+	resolver.add_module("kernelbase.dll",&SQLITE_X64_PE);
+	let graph=resolver.resolve("sqlite3.dll",&SQLITE_X86_PE).unwrap();
+
+	assert_eq!(graph.modules,["sqlite3.dll","kernelbase.dll"]);
+	assert!(graph.dependencies.contains(&Dependency{importer:"sqlite3.dll".into(),module:"api-ms-win-core-file-l1-2-1.dll".into(),kind:DependencyKind::Import}));
+	assert!(graph.dependencies.contains(&Dependency{importer:"api-ms-win-core-file-l1-2-1.dll".into(),module:"kernelbase.dll".into(),kind:DependencyKind::ApiSet}));
+	assert!(!graph.missing_modules.contains(&"api-ms-win-core-file-l1-2-1.dll".to_owned()));
+	assert!(graph.missing_modules.contains(&"api-ms-win-core-winrt-l1-1-0.dll".to_owned()));
+	assert!(graph.missing_symbols.contains(&MissingSymbol{importer:"sqlite3.dll".into(),module:"kernelbase.dll".into(),symbol:Symbol::Name("FlushFileBuffers".into()),reason:MissingReason::NotExported}));
+	// kernelbase.dll imports the same functions from itself
+	assert_eq!(graph.missing_symbols.len(),20);
+}
+
+#[test]
+fn list_relocations() {
+	let block_entries: usize=SQLITE_X86_PE.get_relocations().unwrap().map(|block|block.unwrap().1.len()).sum();
+	assert_eq!(block_entries,9944);
+
+	let entries: Vec<_>=SQLITE_X86_PE.get_relocations().unwrap().entries().map(Result::unwrap).collect();
+	assert_eq!(entries.len(),9820);
+	assert!(entries.iter().all(|entry|entry.rtype==RelocationType::HIGHLOW && entry.param.is_none()));
+	itertools::assert_equal(entries[..3].iter().map(|entry|entry.rva.get()),vec![0x1d37,0x1e0c,0x1f51]);
+
+	let entries: Vec<_>=SQLITE_X64_PE.get_relocations().unwrap().entries().map(Result::unwrap).collect();
+	assert_eq!(entries.len(),1485);
+	assert!(entries.iter().all(|entry|entry.rtype==RelocationType::DIR64));
+	assert_eq!(entries[0].rva.get(),0x120888);
+
+	// HIGHADJ takes the next entry as its parameter
+	let (_,relocs)=SQLITE_X86_PE.get_relocations().unwrap().next().unwrap().unwrap();
+	let mut buf=SQLITE_X86_BUF.clone();
+	let fp=fp_of(&SQLITE_X86_BUF,&relocs[0]);
+	buf[fp+1]=(buf[fp+1]&0xf)|0x40;
+	let pe=Pe::new(&buf).unwrap();
+	let entries: Vec<_>=pe.get_relocations().unwrap().entries().map(Result::unwrap).collect();
+	assert_eq!(entries.len(),9819);
+	assert_eq!(entries[0],RelocationEntry{rva:RVA::new(0x1d37),rtype:RelocationType::HIGHADJ,param:Some(relocs[1].0)});
+	assert_eq!(entries[1].rva.get(),0x1f51);
+
+	// HIGHADJ as the last entry in a block is invalid
+	let mut buf=SQLITE_X86_BUF.clone();
+	let last=relocs.len()-1;
+	let fp=fp_of(&SQLITE_X86_BUF,&relocs[last]);
+	buf[fp+1]=(buf[fp+1]&0xf)|0x40;
+	let valid=relocs[..last].iter().filter(|reloc|reloc.decode(Machine::I386).0!=RelocationType::ABSOLUTE).count();
+	let pe=Pe::new(&buf).unwrap();
+	let mut entries=pe.get_relocations().unwrap().entries();
+	assert_eq!(entries.by_ref().take_while(Result::is_ok).count(),valid);
+	assert!(entries.next().is_none());
+}
+
+#[test]
+fn relocation_types() {
+	use types::Machine::*;
+	use types::RelocationType::*;
+
+	let expected: &[(Machine,[RelocationType;5])]=&[
+		(I386,       [Unknown(5),   Unknown(6),Unknown(7),   Unknown(8),       Unknown(9)]),
+		(AMD64,      [Unknown(5),   Unknown(6),Unknown(7),   Unknown(8),       Unknown(9)]),
+		(ARM64,      [Unknown(5),   Unknown(6),Unknown(7),   Unknown(8),       Unknown(9)]),
+		(ARMNT,      [ARM_MOV32A,   Unknown(6),ARM_MOV32T,   Unknown(8),       Unknown(9)]),
+		(R4000,      [MIPS_JMPADDR, Unknown(6),Unknown(7),   Unknown(8),       MIPS_JMPADDR16]),
+		(IA64,       [Unknown(5),   Unknown(6),Unknown(7),   Unknown(8),       IA64_IMM64]),
+		(RISCV64,    [RISCV_HIGH20, Unknown(6),RISCV_LOW12I, RISCV_LOW12S,     Unknown(9)]),
+		(LOONGARCH64,[Unknown(5),   Unknown(6),Unknown(7),   LOONGARCH_MARK_LA,Unknown(9)]),
+	];
+	for &(machine,ref types) in expected {
+		for raw in 0..16u16 {
+			let (rtype,offset)=Relocation(raw<<12|0x123).decode(machine);
+			assert_eq!(offset,0x123);
+			assert_eq!(rtype.raw() as u16,raw);
+			assert_eq!(Relocation::encode(rtype,0x123),Relocation(raw<<12|0x123));
+			match raw {
+				0 => assert_eq!(rtype,ABSOLUTE),
+				3 => assert_eq!(rtype,HIGHLOW),
+				10 => assert_eq!(rtype,DIR64),
+				5...9 => assert_eq!(rtype,types[raw as usize-5]),
+				_ => {},
+			}
+		}
+	}
+}
+
+/// Lay out the sections of a file at their virtual addresses
+fn map_image(pe: &Pe, buf: &[u8]) -> Vec<u8> {
+	let size_of_headers=*pe.get_optional_header().get_size_of_headers() as usize;
+	let size=pe.get_sections().iter().map(|section|section.virtual_address.get() as usize+section.virtual_size as usize).max().unwrap();
+	let mut image=vec![0u8;size];
+	image[..size_of_headers].copy_from_slice(&buf[..size_of_headers]);
+	for section in pe.get_sections() {
+		let len=::std::cmp::min(section.virtual_size,section.size_of_raw_data) as usize;
+		let (src,dst)=(section.pointer_to_raw_data.get() as usize,section.virtual_address.get() as usize);
+		image[dst..dst+len].copy_from_slice(&buf[src..src+len]);
+	}
+	image
+}
+
+#[test]
+fn rebase_image() {
+	use rebase::{rebase,Layout};
+
+	let relocs: Vec<_>=SQLITE_X86_PE.get_relocations().unwrap().entries().map(Result::unwrap).collect();
+	let mut buf=SQLITE_X86_BUF.clone();
+	rebase(&mut buf,Layout::File,0x20000000,true).unwrap();
+	let pe=Pe::new(&buf).unwrap();
+	assert_eq!(pe.get_optional_header().get_image_base(),0x20000000);
+	for entry in &relocs {
+		let fp=SQLITE_X86_PE.resolve_rva(entry.rva).unwrap().get() as usize;
+		assert_eq!(read_u32(&buf,fp).unwrap().wrapping_sub(read_u32(&SQLITE_X86_BUF,fp).unwrap()),0x10000000);
+	}
+	rebase(&mut buf,Layout::File,0x10000000,true).unwrap();
+	assert!(buf==*SQLITE_X86_BUF);
+
+	// Without updating the header, only the relocations change
+	let mut buf=SQLITE_X86_BUF.clone();
+	rebase(&mut buf,Layout::File,0x20000000,false).unwrap();
+	assert_eq!(Pe::new(&buf).unwrap().get_optional_header().get_image_base(),0x10000000);
+
+	let mut buf=SQLITE_X64_BUF.clone();
+	let base=SQLITE_X64_PE.get_optional_header().get_image_base();
+	rebase(&mut buf,Layout::File,base+0x123450000,true).unwrap();
+	for entry in SQLITE_X64_PE.get_relocations().unwrap().entries().map(Result::unwrap) {
+		let fp=SQLITE_X64_PE.resolve_rva(entry.rva).unwrap().get() as usize;
+		let read_u64=|buf: &[u8]|read_u32(buf,fp).unwrap() as u64 | (read_u32(buf,fp+4).unwrap() as u64)<<32;
+		assert_eq!(read_u64(&buf).wrapping_sub(read_u64(&SQLITE_X64_BUF)),0x123450000);
+	}
+
+	// Mapped layout
+	let mut image=map_image(&SQLITE_X86_PE,&SQLITE_X86_BUF);
+	let original=image.clone();
+	rebase(&mut image,Layout::Mapped,0x20000000,true).unwrap();
+	let pe=Pe::new_mapped(&image).unwrap();
+	assert_eq!(pe.get_optional_header().get_image_base(),0x20000000);
+	for entry in &relocs {
+		let pos=entry.rva.get() as usize;
+		assert_eq!(read_u32(&image,pos).unwrap().wrapping_sub(read_u32(&original,pos).unwrap()),0x10000000);
+	}
+
+	let mut buf=SQLITE_X86_BUF.clone();
+	match rebase(&mut buf,Layout::File,0x100000000,false) {
+		Err(Error::InvalidImageBase) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+	assert!(buf==*SQLITE_X86_BUF);
+
+	// Without relocations, the image can only stay where it is
+	let ddir=SQLITE_X86_PE.get_directory::<RelocationBlock>().unwrap();
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,ddir)+4,0);
+	rebase(&mut buf,Layout::File,0x10000000,true).unwrap();
+	match rebase(&mut buf,Layout::File,0x20000000,true) {
+		Err(Error::NotRelocatable) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+	let mut buf=SQLITE_X86_BUF.clone();
+	buf[fp_of(&SQLITE_X86_BUF,SQLITE_X86_PE.get_header())+22]|=1;
+	match rebase(&mut buf,Layout::File,0x20000000,true) {
+		Err(Error::NotRelocatable) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+}
+
+#[test]
+fn rebase_image_relocation_types() {
+	use rebase::{rebase,Layout};
+
+	// Replace the first relocation with a different type and write test
+	// instructions at its target
+	fn rebase_with(machine: Machine, rtype: u8, target: &[u8], new_base: u64) -> Vec<u8> {
+		let mut buf=SQLITE_X86_BUF.clone();
+		let machine_fp=fp_of(&SQLITE_X86_BUF,SQLITE_X86_PE.get_header())+4;
+		write_u16(&mut buf,machine_fp,machine as u16);
+		let (_,relocs)=SQLITE_X86_PE.get_relocations().unwrap().next().unwrap().unwrap();
+		let fp=fp_of(&SQLITE_X86_BUF,&relocs[0]);
+		buf[fp+1]=(buf[fp+1]&0xf)|(rtype<<4);
+		let target_fp=fp_of(&SQLITE_X86_BUF,SQLITE_X86_PE.ref_at(RVA::<u8>::new(0x1d37)).unwrap());
+		buf[target_fp..target_fp+target.len()].copy_from_slice(target);
+		rebase(&mut buf,Layout::File,new_base,false).unwrap();
+		buf[target_fp..target_fp+target.len()].to_owned()
+	}
+
+	// MOVW r0,#0x1234; MOVT r0,#0x1000
+	let arm=[0x34,0x02,0x01,0xe3, 0x00,0x00,0x41,0xe3];
+	assert_eq!(rebase_with(Machine::ARM,5,&arm,0x20008000),[0x34,0x02,0x09,0xe3, 0x00,0x00,0x42,0xe3]);
+	let thumb=[0x41,0xf2,0x34,0x20, 0xc1,0xf2,0x00,0x00];
+	assert_eq!(rebase_with(Machine::ARMNT,7,&thumb,0x20008000),[0x49,0xf2,0x34,0x20, 0xc2,0xf2,0x00,0x00]);
+	// The high bit of the MOVW immediate is stored separately in Thumb
+	assert_eq!(rebase_with(Machine::ARMNT,7,&thumb,0x10000800),[0x41,0xf6,0x34,0x20, 0xc1,0xf2,0x00,0x00]);
+
+	assert_eq!(rebase_with(Machine::I386,1,&[0x34,0x12],0x2000c000),[0x34,0x22]);
+	assert_eq!(rebase_with(Machine::I386,2,&[0x34,0x12],0x2000c000),[0x34,0xd2]);
+	// The next entry (0x3e0c) is the low half for HIGHADJ
+	assert_eq!(rebase_with(Machine::I386,4,&[0x00,0x10],0x10008000),[0x01,0x10]);
+
+	let mut buf=SQLITE_X86_BUF.clone();
+	let (_,relocs)=SQLITE_X86_PE.get_relocations().unwrap().next().unwrap().unwrap();
+	let fp=fp_of(&SQLITE_X86_BUF,&relocs[0]);
+	buf[fp+1]=(buf[fp+1]&0xf)|0x50;
+	match rebase(&mut buf,Layout::File,0x20000000,true) {
+		Err(Error::UnsupportedRelocation) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+	assert!(buf[..fp]==SQLITE_X86_BUF[..fp]);
+}
+
+#[test]
+fn build_relocations() {
+	for &(pe,buf) in &[(&*SQLITE_X86_PE,&*SQLITE_X86_BUF),(&*SQLITE_X64_PE,&*SQLITE_X64_BUF)] {
+		let mut builder=RelocationBuilder::new();
+		for entry in pe.get_relocations().unwrap().entries() {
+			builder.add_entry(entry.unwrap());
+		}
+		let ddir=pe.get_directory::<RelocationBlock>().unwrap();
+		let fp=pe.resolve_rva(ddir.virtual_address).unwrap().get() as usize;
+		assert!(builder.build()==&buf[fp..fp+ddir.size as usize]);
+	}
+
+	let mut builder=RelocationBuilder::new();
+	builder.add(RVA::new(0x2010),RelocationType::DIR64);
+	builder.add_highadj(RVA::new(0x1004),0xabcd);
+	builder.add(RVA::new(0x1ffe),RelocationType::HIGHLOW);
+	builder.add(RVA::new(0x1000),RelocationType::HIGH);
+	let table=builder.build();
+	assert_eq!(table.len(),2*8+4*2+2*2);
+
+	let words: Vec<u16>=(0..table.len()/2).map(|i|read_u16(&table,i*2).unwrap()).collect();
+	assert_eq!(words,[
+		0x1000,0, 16,0, 0x1000,0x4004,0xabcd,0x3ffe,
+		0x2000,0, 12,0, 0xa010,0x0000,
+	]);
+}
+
+/// A version 2 dynamic relocation with 64-bit symbols
+fn push_dynamic_relocation_v2(buf: &mut Vec<u8>, symbol: u64, header: &[u8], fixup_info: &[u8]) {
+	push_u32(buf,24+header.len() as u32);
+	push_u32(buf,fixup_info.len() as u32);
+	push_u32(buf,symbol as u32);
+	push_u32(buf,(symbol>>32) as u32);
+	push_u32(buf,0);
+	push_u32(buf,0);
+	buf.extend_from_slice(header);
+	buf.extend_from_slice(fixup_info);
+}
+
+fn relocation_block(page_rva: u32, entries: &[u8]) -> Vec<u8> {
+	let mut block=vec![];
+	push_u32(&mut block,page_rva);
+	push_u32(&mut block,8+entries.len() as u32);
+	block.extend_from_slice(entries);
+	block
+}
+
+/// Serialize 16-bit (`size` 2) or 32-bit (`size` 4) little-endian words
+fn le_words(words: &[u32], size: usize) -> Vec<u8> {
+	let mut buf=vec![];
+	for &word in words {
+		match size {
+			2 => push_u16(&mut buf,word as u16),
+			4 => push_u32(&mut buf,word),
+			_ => unreachable!(),
+		}
+	}
+	buf
+}
+
+#[test]
+fn load_config() {
+	for &(pe,size) in &[(&*SQLITE_X86_PE,0x5c),(&*SQLITE_X64_PE,0x94)] {
+		let lc=pe.get_load_config().unwrap();
+		assert_eq!(lc.get_size(),size);
+		assert_eq!(lc.get_dynamic_value_reloc_table(),None);
+		match pe.get_dynamic_relocations() {
+			Err(Error::DirectoryMissing) => {},
+			Err(e) => panic!("unexpected error: {:?}",e),
+			Ok(_) => panic!("unexpected dynamic relocations"),
+		}
+	}
+	match SQLITE_X86_PE.get_load_config().unwrap() {
+		LoadConfig::Pe32(lc) => {
+			assert!({lc.security_cookie}!=0);
+			assert_eq!({lc.se_handler_count},1);
+			assert!({lc.guard_cf_check_function_pointer}!=0);
+			assert_eq!({lc.guard_flags},0x100);
+			assert_eq!({lc.guard_address_taken_iat_entry_table},0);
+		},
+		_ => panic!("wrong load config type"),
+	}
+	match SQLITE_X64_PE.get_load_config().unwrap() {
+		LoadConfig::Pe32Plus(lc) => assert!({lc.security_cookie}!=0),
+		_ => panic!("wrong load config type"),
+	}
+}
+
+#[test]
+fn dynamic_relocations() {
+	use dvrt::*;
+
+	let mut entries=vec![];
+	push_dynamic_relocation_v2(&mut entries,1,&[2,0x48,0x89],&[]);
+	push_dynamic_relocation_v2(&mut entries,3,&[],&relocation_block(0x1000,&le_words(&[0x123|0x1000|5<<13,0x456|7<<13],4)));
+	push_dynamic_relocation_v2(&mut entries,4,&[],&relocation_block(0x2000,&le_words(&[0x010|0x1000|0x4000,0],2)));
+	push_dynamic_relocation_v2(&mut entries,5,&[],&relocation_block(0x3000,&le_words(&[0x020|3<<12,0x030|0xf<<12],2)));
+	let mut arm64x=le_words(&[0x100|2<<14,0x108|1<<12|3<<14],2);
+	arm64x.extend_from_slice(&[1,2,3,4,5,6,7,8]);
+	arm64x.extend_from_slice(&le_words(&[0x200|2<<12|3<<14,3,0x204|2<<12,5],2));
+	push_dynamic_relocation_v2(&mut entries,6,&[],&relocation_block(0x4000,&arm64x));
+	push_dynamic_relocation_v2(&mut entries,0x180001000,&[],&relocation_block(0x5000,&le_words(&[0xa008,0],2)));
+
+	// Put a load config referencing the table in the .reloc section
+	let mut buf=SQLITE_X64_BUF.clone();
+	let reloc_section=SQLITE_X64_PE.get_sections().iter().position(|section|section.name.as_os_str()==".reloc").unwrap();
+	let section=&SQLITE_X64_PE.get_sections()[reloc_section];
+	let section_fp=section.pointer_to_raw_data.get() as usize;
+	let lc_size=size_of::<LoadConfigDirectory64>();
+	for b in &mut buf[section_fp..section_fp+lc_size] {
+		*b=0;
+	}
+	write_u32(&mut buf,section_fp,lc_size as u32);
+	write_u32(&mut buf,section_fp+224,lc_size as u32);
+	buf[section_fp+228]=reloc_section as u8+1;
+	let mut table=vec![];
+	push_u32(&mut table,2);
+	push_u32(&mut table,entries.len() as u32);
+	table.extend_from_slice(&entries);
+	buf[section_fp+lc_size..section_fp+lc_size+table.len()].copy_from_slice(&table);
+	let ddir=SQLITE_X64_PE.get_directory_raw(DirectoryEntry::LoadConfigTable).unwrap();
+	let ddir_fp=fp_of(&SQLITE_X64_BUF,ddir);
+	write_u32(&mut buf,ddir_fp,section.virtual_address.get());
+	write_u32(&mut buf,ddir_fp+4,lc_size as u32);
+
+	let pe=Pe::new(&buf).unwrap();
+	assert_eq!(pe.get_load_config().unwrap().get_dynamic_value_reloc_table(),Some((lc_size as u32,reloc_section as u16+1)));
+	let relocs: Vec<_>=pe.get_dynamic_relocations().unwrap().map(Result::unwrap).collect();
+	assert_eq!(relocs.iter().map(|r|r.symbol).collect::<Vec<_>>(),[1,3,4,5,6,0x180001000]);
+	assert_eq!(relocs[0].header,[2,0x48,0x89]);
+	assert_eq!(relocs[0].get_fixups().unwrap(),[]);
+	let mut overflow=buf.clone();
+	write_u32(&mut overflow,section_fp+lc_size+4,0xfffffffc);
+	match Pe::new(&overflow).unwrap().get_dynamic_relocations() {
+		Err(Error::InvalidSize) => {},
+		other => panic!("unexpected result: {:?}",other.map(|_|())),
+	}
+
+	let fixups: Vec<_>=relocs[1..].iter().flat_map(|r|r.get_fixups().unwrap()).collect();
+	assert_eq!(fixups,[
+		DynamicFixup{rva:RVA::new(0x1123),kind:DynamicFixupKind::ImportControlTransfer{indirect_call:true,iat_index:5}},
+		DynamicFixup{rva:RVA::new(0x1456),kind:DynamicFixupKind::ImportControlTransfer{indirect_call:false,iat_index:7}},
+		DynamicFixup{rva:RVA::new(0x2010),kind:DynamicFixupKind::IndirControlTransfer{indirect_call:true,rex_w_prefix:false,cfg_check:true}},
+		DynamicFixup{rva:RVA::new(0x3020),kind:DynamicFixupKind::SwitchTableBranch{register_number:3}},
+		DynamicFixup{rva:RVA::new(0x3030),kind:DynamicFixupKind::SwitchTableBranch{register_number:15}},
+		DynamicFixup{rva:RVA::new(0x4100),kind:DynamicFixupKind::Arm64X(Arm64XFixup::ZeroFill(4))},
+		DynamicFixup{rva:RVA::new(0x4108),kind:DynamicFixupKind::Arm64X(Arm64XFixup::Value(&[1,2,3,4,5,6,7,8]))},
+		DynamicFixup{rva:RVA::new(0x4200),kind:DynamicFixupKind::Arm64X(Arm64XFixup::Delta(-24))},
+		DynamicFixup{rva:RVA::new(0x4204),kind:DynamicFixupKind::Arm64X(Arm64XFixup::Delta(20))},
+		DynamicFixup{rva:RVA::new(0x5008),kind:DynamicFixupKind::Relocation(RelocationType::DIR64)},
+	]);
+
+	// Version 1 with 32-bit symbols
+	let mut table=vec![];
+	push_u32(&mut table,1);
+	push_u32(&mut table,2*8+12+16);
+	let block=relocation_block(0x7000,&le_words(&[0x044|0x1000,0x2048],2));
+	push_u32(&mut table,5);
+	push_u32(&mut table,block.len() as u32);
+	table.extend_from_slice(&block);
+	let block=relocation_block(0x8000,&le_words(&[0x10|1<<10|17<<11|1<<16|300<<17,0x20],4));
+	push_u32(&mut table,8);
+	push_u32(&mut table,block.len() as u32);
+	table.extend_from_slice(&block);
+	let relocs: Vec<_>=DynamicRelocationIter::new(&table,false,Machine::ARM64).unwrap().map(Result::unwrap).collect();
+	assert_eq!(relocs.len(),2);
+	assert_eq!(relocs[0].get_fixups().unwrap(),[
+		DynamicFixup{rva:RVA::new(0x7044),kind:DynamicFixupKind::SwitchTableBranch{register_number:1}},
+		DynamicFixup{rva:RVA::new(0x7048),kind:DynamicFixupKind::SwitchTableBranch{register_number:2}},
+	]);
+	assert_eq!(relocs[1].get_fixups().unwrap(),[
+		DynamicFixup{rva:RVA::new(0x8040),kind:DynamicFixupKind::Arm64KernelImportCallTransfer{indirect_call:true,register_index:17,import_type:true,iat_index:300}},
+		DynamicFixup{rva:RVA::new(0x8080),kind:DynamicFixupKind::Arm64KernelImportCallTransfer{indirect_call:false,register_index:0,import_type:false,iat_index:0}},
+	]);
+
+	// Function overrides
+	let mut info=vec![];
+	push_u32(&mut info,16+4+12);
+	info.extend_from_slice(&le_words(&[0x1000,0,4,12,0x2000],4));
+	info.extend_from_slice(&relocation_block(0x1000,&le_words(&[0xa010,0],2)));
+	info.extend_from_slice(&le_words(&[1,8],4));
+	info.extend_from_slice(&le_words(&[1,2],2));
+	info.extend_from_slice(&le_words(&[0x2000],4));
+	let mut table=vec![];
+	push_u32(&mut table,2);
+	push_u32(&mut table,24+info.len() as u32);
+	push_dynamic_relocation_v2(&mut table,7,&[],&info);
+	let reloc=DynamicRelocationIter::new(&table,true,Machine::AMD64).unwrap().next().unwrap().unwrap();
+	match reloc.get_fixups() {
+		Err(Error::UnsupportedRelocation) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+	let overrides=reloc.get_function_overrides().unwrap();
+	assert_eq!(overrides.overrides.len(),1);
+	assert_eq!(overrides.overrides[0].original_rva.get(),0x1000);
+	assert_eq!(overrides.overrides[0].override_rvas,[RVA::<()>::new(0x2000)]);
+	assert_eq!(overrides.overrides[0].fixups,[DynamicFixup{rva:RVA::new(0x1010),kind:DynamicFixupKind::Relocation(RelocationType::DIR64)}]);
+	assert_eq!(overrides.bdd_version,1);
+	assert_eq!(overrides.bdd_nodes,[BddNode{left:1,right:2,value:0x2000}]);
+
+	// Truncated and unsupported tables
+	table[4]+=1;
+	match DynamicRelocationIter::new(&table,true,Machine::AMD64) {
+		Err(Error::InvalidSize) => {},
+		other => panic!("unexpected result: {:?}",other.map(|_|())),
+	}
+	table[4]-=1;
+	table[0]=3;
+	match DynamicRelocationIter::new(&table,true,Machine::AMD64) {
+		Err(Error::UnsupportedVersion) => {},
+		other => panic!("unexpected result: {:?}",other.map(|_|())),
+	}
+}
+
+#[test]
+fn list_resources() {
+	use resources::*;
+
+	for pe in &[&*SQLITE_X86_PE,&*SQLITE_X64_PE] {
+		let resources=pe.get_resources().unwrap();
+		let list: Vec<_>=resources.list().unwrap().iter().map(|r|(r.rtype,r.name,r.lang,r.data.get_data().unwrap().len())).collect();
+		assert_eq!(list,[(RT_ICON,ResourceId::Id(1),1033,2216),(RT_GROUP_ICON,ResourceId::Id(101),1033,20),(RT_VERSION,ResourceId::Id(1),1033,1056)]);
+		let root=resources.get_root().unwrap();
+		assert_eq!({root.get_directory().number_of_id_entries},3);
+		assert_eq!(root.entries().map(|e|e.get_id().unwrap().to_string()).collect::<Vec<_>>(),["#3","#14","#16"]);
+		let group=resources.list().unwrap()[1].data.get_data().unwrap();
+		assert_eq!(&group[..6],[0,0,1,0,1,0]);
+	}
+
+	// Give the icon group a name, stored over the start of the icon data
+	let mut buf=SQLITE_X86_BUF.clone();
+	let pe=&*SQLITE_X86_PE;
+	let resources=pe.get_resources().unwrap();
+	let base=pe.get_directory::<ResourceDirectory>().unwrap().virtual_address.get();
+	let icon=resources.list().unwrap()[0].data.get_entry();
+	let name_fp=pe.resolve_rva(icon.data.offset(0) as RVA<u8>).unwrap().get() as usize;
+	buf[name_fp..name_fp+10].copy_from_slice(&[4,0,b'M',0,b'A',0,b'I',0,b'N',0]);
+	let group_type=resources.get_root().unwrap().entries().nth(1).unwrap().get_node().unwrap().into_directory().unwrap();
+	let group_entry=group_type.entries().next().unwrap().get_entry();
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,group_entry),RESOURCE_NAME_IS_STRING|(icon.data.get()-base));
+
+	let pe=Pe::new(&buf).unwrap();
+	let list=pe.get_resources().unwrap().list().unwrap();
+	let name: Vec<u16>="MAIN".encode_utf16().collect();
+	assert_eq!(list[1].name,ResourceId::Name(&name));
+	assert_eq!(list[1].name.to_string(),"MAIN");
+	match pe.get_resources().unwrap().get_root().unwrap().entries().next().unwrap().get_node() {
+		Ok(ResourceNode::Directory(_)) => {},
+		_ => panic!("expected a directory"),
+	}
+	match pe.get_resources().unwrap().get_root().unwrap().entries().next().unwrap().get_node().unwrap().into_data() {
+		Err(Error::InvalidResource) => {},
+		_ => panic!("expected InvalidResource"),
+	}
+}
+
+#[test]
+fn find_resources() {
+	use resources::*;
+
+	let pe=&*SQLITE_X86_PE;
+	let resources=pe.get_resources().unwrap();
+	for &lang in &[Some(1033),Some(0x809),Some(0x407),None] {
+		let version=resources.find(RT_VERSION,ResourceId::Id(1),lang).unwrap();
+		assert_eq!(version.lang,1033);
+		assert_eq!(version.data.get_data().unwrap().len(),1056);
+	}
+	assert_eq!(pe.find_resource(RT_GROUP_ICON,ResourceId::Id(101),None).unwrap().len(),20);
+	for &(rtype,name) in &[(RT_MANIFEST,ResourceId::Id(1)),(RT_VERSION,ResourceId::Id(2))] {
+		match pe.find_resource(rtype,name,Some(1033)) {
+			Err(Error::ResourceNotFound) => {},
+			other => panic!("unexpected result: {:?}",other),
+		}
+	}
+
+	// Change the language of the version resource to German (neutral
+	// sublanguage) and name it
+	let mut buf=SQLITE_X86_BUF.clone();
+	let base=pe.get_directory::<ResourceDirectory>().unwrap().virtual_address.get();
+	let version_names=resources.get_root().unwrap().find(&RT_VERSION).unwrap().get_node().unwrap().into_directory().unwrap();
+	let name_entry=version_names.entries().next().unwrap();
+	let lang_entry=name_entry.get_node().unwrap().into_directory().unwrap().entries().next().unwrap();
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,lang_entry.get_entry()),0x0007);
+	let icon=resources.find(RT_ICON,ResourceId::Id(1),None).unwrap().data.get_entry();
+	let name_fp=pe.resolve_rva(icon.data.offset(0) as RVA<u8>).unwrap().get() as usize;
+	buf[name_fp..name_fp+10].copy_from_slice(&[4,0,b'I',0,b'N',0,b'F',0,b'O',0]);
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,name_entry.get_entry()),RESOURCE_NAME_IS_STRING|(icon.data.get()-base));
+
+	let pe=Pe::new(&buf).unwrap();
+	let resources=pe.get_resources().unwrap();
+	let name: Vec<u16>="Info".encode_utf16().collect();
+	for &lang in &[Some(0x807),Some(0x40c),None] {
+		let version=resources.find(RT_VERSION,ResourceId::Name(&name),lang).unwrap();
+		assert_eq!(version.lang,7);
+		assert_eq!(version.name.to_string(),"INFO");
+	}
+	match resources.find(RT_VERSION,ResourceId::Id(1),None) {
+		Err(Error::ResourceNotFound) => {},
+		_ => panic!("expected ResourceNotFound"),
+	}
+}
+
+#[test]
+fn version_info() {
+	use resources::version::VersionInfo;
+
+	for pe in &[&*SQLITE_X86_PE,&*SQLITE_X64_PE] {
+		let info=pe.get_version_info().unwrap();
+		assert_eq!(info.get_file_version(),Some([3,10,1,0]));
+		assert_eq!(info.get_product_version(),Some([3,10,1,0]));
+		assert_eq!(info.get_file_flags(),Some(file_flags::FileFlags::empty()));
+		assert_eq!(info.translations,[(1033,1200)]);
+		assert_eq!(info.string_tables.len(),1);
+		assert_eq!(info.string_tables[0].get_language(),Some(1033));
+		assert_eq!(info.string_tables[0].get_code_page(),Some(1200));
+		assert_eq!(info.get_string("CompanyName"),Some("SQLite Development Team"));
+		assert_eq!(info.get_string("OriginalFilename"),None);
+		assert_eq!(info.get_string("ProductVersion"),Some("3.10.1"));
+		assert_eq!(info.string_tables[0].strings.len(),8);
+	}
+
+	// Malformed lengths must not cause panics
+	let data=SQLITE_X86_PE.find_resource(resources::RT_VERSION,resources::ResourceId::Id(1),None).unwrap();
+	for len in 0..data.len() {
+		let _=VersionInfo::parse(&data[..len]);
+	}
+	let mut data=data.to_owned();
+	for pos in 0..data.len()/2 {
+		let original=(data[pos*2],data[pos*2+1]);
+		for &value in &[0u16,1,6,0x7fff,0xffff] {
+			write_u16(&mut data,pos*2,value);
+			let _=VersionInfo::parse(&data);
+		}
+		data[pos*2]=original.0;
+		data[pos*2+1]=original.1;
+	}
+}
+
+const TEST_MANIFEST: &'static str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<!-- Test manifest -->
+<assembly xmlns="urn:schemas-microsoft-com:asm.v1" manifestVersion="1.0" xmlns:asmv3="urn:schemas-microsoft-com:asm.v3">
+  <assemblyIdentity type="win32" name="SQLite.Shell" version="3.10.1.0" processorArchitecture="x86"/>
+  <description>SQLite &amp; friends</description>
+  <trustInfo xmlns="urn:schemas-microsoft-com:asm.v2">
+    <security>
+      <requestedPrivileges>
+        <requestedExecutionLevel level='requireAdministrator' uiAccess="false"/>
+      </requestedPrivileges>
+    </security>
+  </trustInfo>
+  <compatibility xmlns="urn:schemas-microsoft-com:compatibility.v1">
+    <application>
+      <supportedOS Id="{35138b9a-5d96-4fbd-8e2d-a2440225f93a}"/>
+      <supportedOS Id="{8e0f7a12-bfb3-4fe8-b9a5-48fd50a15a9a}"/>
+    </application>
+  </compatibility>
+  <asmv3:application>
+    <asmv3:windowsSettings xmlns="http://schemas.microsoft.com/SMI/2005/WindowsSettings">
+      <dpiAware> true/pm </dpiAware>
+    </asmv3:windowsSettings>
+  </asmv3:application>
+  <dependency>
+    <dependentAssembly>
+      <assemblyIdentity type="win32" name="Microsoft.Windows.Common-Controls" version="6.0.0.0" processorArchitecture="*" publicKeyToken="6595b64144ccf1df" language="*"/>
+    </dependentAssembly>
+  </dependency>
+</assembly>
+"#;
+
+#[test]
+fn manifest() {
+	use resources::manifest::*;
+
+	let manifest=Manifest::parse(TEST_MANIFEST.as_bytes()).unwrap();
+	let identity=manifest.identity.as_ref().unwrap();
+	assert_eq!(identity.name.as_ref().unwrap(),"SQLite.Shell");
+	assert_eq!(identity.public_key_token,None);
+	assert_eq!(manifest.requested_execution_level,Some(RequestedExecutionLevel{level:ExecutionLevel::RequireAdministrator,ui_access:false}));
+	assert!(manifest.requires_administrator());
+	assert_eq!(manifest.dpi_aware.as_ref().unwrap(),"true/pm");
+	assert_eq!(manifest.dpi_awareness,None);
+	assert_eq!(manifest.supported_os,["{35138b9a-5d96-4fbd-8e2d-a2440225f93a}","{8e0f7a12-bfb3-4fe8-b9a5-48fd50a15a9a}"]);
+	assert_eq!(manifest.dependencies,[AssemblyIdentity{
+		assembly_type:Some("win32".to_owned()),
+		name:Some("Microsoft.Windows.Common-Controls".to_owned()),
+		version:Some("6.0.0.0".to_owned()),
+		processor_architecture:Some("*".to_owned()),
+		public_key_token:Some("6595b64144ccf1df".to_owned()),
+		language:Some("*".to_owned()),
+	}]);
+
+	// UTF-16 with byte order mark
+	let mut utf16=vec![0xff,0xfe];
+	for c in TEST_MANIFEST.encode_utf16() {
+		push_u16(&mut utf16,c);
+	}
+	assert_eq!(Manifest::parse(&utf16).unwrap().dependencies.len(),1);
+	let utf16be: Vec<u8>=utf16.chunks(2).flat_map(|c|vec![c[1],c[0]]).collect();
+	assert_eq!(Manifest::parse(&utf16be).unwrap().dependencies.len(),1);
+
+	for xml in &["<assembly>","<assembly></application>","<assembly a=b/>","<other/>","<assembly/><assembly/>","<assembly>&bogus;</assembly>"] {
+		match Manifest::parse(xml.as_bytes()) {
+			Err(Error::InvalidResource) => {},
+			other => panic!("unexpected result for {}: {:?}",xml,other),
+		}
+	}
+	assert!(Manifest::parse(b"<assembly><![CDATA[<not a tag>]]></assembly>").unwrap().identity.is_none());
+
+	match SQLITE_X86_PE.get_manifest() {
+		Err(Error::ResourceNotFound) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+
+	// Turn the icon group into an isolation aware manifest, stored over the
+	// icon data
+	let mut buf=SQLITE_X86_BUF.clone();
+	let pe=&*SQLITE_X86_PE;
+	let list=pe.get_resources().unwrap().list().unwrap();
+	let icon=list[0].data.get_entry();
+	let fp=pe.resolve_rva(icon.data.offset(0) as RVA<u8>).unwrap().get() as usize;
+	let xml=b"<assembly><trustInfo><security><requestedPrivileges><requestedExecutionLevel level=\"asInvoker\"/></requestedPrivileges></security></trustInfo></assembly>";
+	buf[fp..fp+xml.len()].copy_from_slice(xml);
+	let group=list[1].data.get_entry();
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,group),icon.data.get());
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,group)+4,xml.len() as u32);
+	let group_type=pe.get_resources().unwrap().get_root().unwrap().find(&resources::RT_GROUP_ICON).unwrap();
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,group_type.get_entry()),24);
+	let group_name=group_type.get_node().unwrap().into_directory().unwrap().entries().next().unwrap();
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,group_name.get_entry()),ISOLATIONAWARE_MANIFEST_RESOURCE_ID as u32);
+
+	let pe=Pe::new(&buf).unwrap();
+	assert_eq!(pe.get_manifest_data().unwrap(),&xml[..]);
+	let manifest=pe.get_manifest().unwrap();
+	assert_eq!(manifest.requested_execution_level.unwrap().level,ExecutionLevel::AsInvoker);
+	assert!(manifest.dependencies.is_empty());
+}
+
+#[test]
+fn icons() {
+	use resources::*;
+	use resources::icon::*;
+
+	let pe=&*SQLITE_X86_PE;
+	let group=pe.get_icon_group(ResourceId::Id(101),None).unwrap();
+	assert!(!group.cursor);
+	assert_eq!(group.entries,[IconGroupEntry{width:32,height:32,color_count:0,planes:1,bit_count:8,bytes_in_res:2216,id:1}]);
+	let image=pe.find_resource(RT_ICON,ResourceId::Id(1),None).unwrap();
+	let ico=pe.get_icon_file(ResourceId::Id(101),Some(1033)).unwrap();
+	assert_eq!(ico.len(),6+16+2216);
+	assert_eq!(&ico[..22],[0,0,1,0,1,0, 32,32,0,0,1,0,8,0,0xa8,8,0,0,22,0,0,0]);
+	assert!(&ico[22..]==image);
+	match pe.get_cursor_file(ResourceId::Id(101),None) {
+		Err(Error::ResourceNotFound) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+
+	// Turn the icon into a cursor with hotspot (5,7)
+	let mut buf=SQLITE_X86_BUF.clone();
+	let root=pe.get_resources().unwrap().get_root().unwrap();
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,root.find(&RT_ICON).unwrap().get_entry()),1);
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,root.find(&RT_GROUP_ICON).unwrap().get_entry()),12);
+	let group_fp=fp_of(&SQLITE_X86_BUF,&pe.find_resource(RT_GROUP_ICON,ResourceId::Id(101),None).unwrap()[0]);
+	buf[group_fp..group_fp+20].copy_from_slice(&[0,0,2,0,1,0, 32,0,64,0,1,0,1,0,0xa8,8,0,0,1,0]);
+	let image_fp=fp_of(&SQLITE_X86_BUF,&image[0]);
+	buf[image_fp..image_fp+4].copy_from_slice(&[5,0,7,0]);
+
+	let pe=Pe::new(&buf).unwrap();
+	let group=pe.get_cursor_group(ResourceId::Id(101),None).unwrap();
+	assert!(group.cursor);
+	assert_eq!(group.entries,[IconGroupEntry{width:32,height:32,color_count:0,planes:1,bit_count:1,bytes_in_res:2216,id:1}]);
+	let cur=pe.get_cursor_file(ResourceId::Id(101),None).unwrap();
+	assert_eq!(cur.len(),6+16+2212);
+	assert_eq!(&cur[..22],[0,0,2,0,1,0, 32,32,0,0,5,0,7,0,0xa4,8,0,0,22,0,0,0]);
+	assert!(&cur[22..]==&image[4..]);
+
+	match IconGroup::parse(&[0,0,3,0,0,0]) {
+		Err(Error::InvalidResource) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+	match IconGroup::parse(&[0,0,1,0,2,0]) {
+		Err(Error::Io(_)) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+}
+
+#[test]
+fn string_and_message_tables() {
+	use resources::*;
+	use resources::strings::*;
+
+	let mut block=vec![];
+	for s in &["","Hello","","World"] {
+		push_u16(&mut block,s.len() as u16);
+		for c in s.encode_utf16() {
+			push_u16(&mut block,c);
+		}
+	}
+	let parsed=StringBlock::parse(&block).unwrap();
+	assert_eq!(parsed.strings.len(),STRINGS_PER_BLOCK);
+	assert_eq!(parsed.get(0x31),Some("Hello"));
+	assert_eq!(parsed.get(2),None);
+	assert_eq!(string_block_id(0x31),4);
+
+	let mut table=le_words(&[2, 1,2,28, 0x10,0x10,56],4);
+	push_u16(&mut table,16);
+	push_u16(&mut table,1);
+	table.extend(le_words(&"One\r\n\0".encode_utf16().map(|c|c as u32).collect::<Vec<_>>(),2));
+	push_u16(&mut table,12);
+	push_u16(&mut table,0);
+	table.extend_from_slice(b"Tw\xe9\r\n\0\0\0");
+	push_u16(&mut table,12);
+	push_u16(&mut table,2);
+	table.extend_from_slice(b"Sixteen\0");
+	let parsed=MessageTable::parse(&table).unwrap();
+	assert_eq!(parsed.messages,[(1,"One\r\n".to_owned()),(2,"Tw\u{e9}\r\n".to_owned()),(0x10,"Sixteen".to_owned())]);
+	assert_eq!(parsed.get(0x10),Some("Sixteen"));
+	assert_eq!(parsed.get(3),None);
+
+	// Turn the icon into string block 1 and the icon group into a message
+	// table stored after the strings
+	let mut buf=SQLITE_X86_BUF.clone();
+	let pe=&*SQLITE_X86_PE;
+	let resources=pe.get_resources().unwrap();
+	let root=resources.get_root().unwrap();
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,root.find(&RT_ICON).unwrap().get_entry()),6);
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,root.find(&RT_GROUP_ICON).unwrap().get_entry()),11);
+	let icon=resources.find(RT_ICON,ResourceId::Id(1),None).unwrap().data.get_entry();
+	let icon_fp=pe.resolve_rva(icon.data.offset(0) as RVA<u8>).unwrap().get() as usize;
+	buf[icon_fp..icon_fp+block.len()].copy_from_slice(&block);
+	buf[icon_fp+256..icon_fp+256+table.len()].copy_from_slice(&table);
+	let group=resources.find(RT_GROUP_ICON,ResourceId::Id(101),None).unwrap().data.get_entry();
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,group),icon.data.get()+256);
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,group)+4,table.len() as u32);
+
+	let pe=Pe::new(&buf).unwrap();
+	assert_eq!(pe.get_string(1,Some(1033)).unwrap(),"Hello");
+	assert_eq!(pe.get_string(3,None).unwrap(),"World");
+	for &id in &[0,2,15,16] {
+		match pe.get_string(id,None) {
+			Err(Error::ResourceNotFound) => {},
+			other => panic!("unexpected result for {}: {:?}",id,other),
+		}
+	}
+	assert_eq!(pe.get_message(2,Some(1033)).unwrap(),"Tw\u{e9}\r\n");
+	assert_eq!(pe.get_message(0x10,None).unwrap(),"Sixteen");
+	match pe.get_message(3,None) {
+		Err(Error::ResourceNotFound) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+
+	// Only the requested message is decoded
+	let mut buf=buf.clone();
+	buf[icon_fp+256+58]=7;
+	let pe=Pe::new(&buf).unwrap();
+	assert_eq!(pe.get_message(2,None).unwrap(),"Tw\u{e9}\r\n");
+	match pe.get_message(0x10,None) {
+		Err(Error::InvalidResource) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+
+	// Zero-length entries would never advance
+	table[28..30].copy_from_slice(&[0,0]);
+	match MessageTable::parse(&table) {
+		Err(Error::InvalidResource) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+}
+
+fn push_utf16z(buf: &mut Vec<u8>, s: &str) {
+	for c in s.encode_utf16() {
+		push_u16(buf,c);
+	}
+	push_u16(buf,0);
+}
+
+fn pad4(buf: &mut Vec<u8>) {
+	while buf.len()%4!=0 {
+		buf.push(0);
+	}
+}
+
+#[test]
+fn dialogs() {
+	use resources::*;
+	use resources::dialog::*;
+
+	let mut data=le_words(&[DS_SETFONT|0x80c80000,0],4);
+	data.extend(le_words(&[2,0,0,200,100, 0,0],2));
+	push_utf16z(&mut data,"About");
+	push_u16(&mut data,8);
+	push_utf16z(&mut data,"MS Shell Dlg");
+	pad4(&mut data);
+	data.extend(le_words(&[0x50010001,0],4));
+	data.extend(le_words(&[10,80,50,14,1, 0xffff,0x80],2));
+	push_utf16z(&mut data,"OK");
+	push_u16(&mut data,0);
+	pad4(&mut data);
+	data.extend(le_words(&[0x50000000,0x200],4));
+	data.extend(le_words(&[10,10,180,60,0xffff],2));
+	push_utf16z(&mut data,"SysListView32");
+	data.extend(le_words(&[0xffff,101, 2,0xcdab],2));
+
+	let dialog=Dialog::parse(&data).unwrap();
+	assert!(!dialog.extended);
+	assert_eq!((dialog.style,dialog.cx,dialog.cy),(DS_SETFONT|0x80c80000,200,100));
+	assert_eq!((&dialog.menu,&dialog.class,&*dialog.caption),(&NameOrOrdinal::None,&NameOrOrdinal::None,"About"));
+	assert_eq!(dialog.font,Some(DialogFont{point_size:8,weight:0,italic:false,charset:0,typeface:"MS Shell Dlg".to_owned()}));
+	assert_eq!(dialog.controls,[
+		DialogControl{help_id:0,style:0x50010001,ex_style:0,x:10,y:80,cx:50,cy:14,id:1,class:ControlClass::Button,title:NameOrOrdinal::Name("OK".to_owned()),extra:vec![]},
+		DialogControl{help_id:0,style:0x50000000,ex_style:0x200,x:10,y:10,cx:180,cy:60,id:0xffff,class:ControlClass::Name("SysListView32".to_owned()),title:NameOrOrdinal::Ordinal(101),extra:vec![0xab,0xcd]},
+	]);
+	match Dialog::parse(&data[..data.len()-1]) {
+		Err(Error::InvalidSize) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+
+	let mut ex=le_words(&[1,0xffff],2);
+	ex.extend(le_words(&[7,0,DS_SHELLFONT|0x80c80000],4));
+	ex.extend(le_words(&[1,0xfff6,0,100,50, 0xffff,0x66],2));
+	push_utf16z(&mut ex,"MyDlg");
+	push_utf16z(&mut ex,"Settings");
+	ex.extend(le_words(&[9,400,0x0101],2));
+	push_utf16z(&mut ex,"Segoe UI");
+	pad4(&mut ex);
+	ex.extend(le_words(&[3,0,0x50010000],4));
+	ex.extend(le_words(&[1,2,3,4],2));
+	ex.extend(le_words(&[0x10000],4));
+	ex.extend(le_words(&[0xffff,0x81, 0, 0],2));
+
+	let dialog=Dialog::parse(&ex).unwrap();
+	assert!(dialog.extended);
+	assert_eq!((dialog.help_id,dialog.style,dialog.x),(7,DS_SHELLFONT|0x80c80000,-10));
+	assert_eq!((&dialog.menu,&dialog.class,&*dialog.caption),(&NameOrOrdinal::Ordinal(0x66),&NameOrOrdinal::Name("MyDlg".to_owned()),"Settings"));
+	assert_eq!(dialog.font,Some(DialogFont{point_size:9,weight:400,italic:true,charset:1,typeface:"Segoe UI".to_owned()}));
+	assert_eq!(dialog.controls,[
+		DialogControl{help_id:3,style:0x50010000,ex_style:0,x:1,y:2,cx:3,cy:4,id:0x10000,class:ControlClass::Edit,title:NameOrOrdinal::None,extra:vec![]},
+	]);
+
+	// Store the dialog in place of the icon
+	let mut buf=SQLITE_X86_BUF.clone();
+	let pe=&*SQLITE_X86_PE;
+	let resources=pe.get_resources().unwrap();
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,resources.get_root().unwrap().find(&RT_ICON).unwrap().get_entry()),5);
+	let icon=pe.find_resource(RT_ICON,ResourceId::Id(1),None).unwrap();
+	let icon_fp=fp_of(&SQLITE_X86_BUF,&icon[0]);
+	buf[icon_fp..icon_fp+ex.len()].copy_from_slice(&ex);
+	let pe=Pe::new(&buf).unwrap();
+	let dialog=pe.get_dialog(ResourceId::Id(1),Some(1033)).unwrap();
+	assert_eq!(dialog.caption,"Settings");
+	assert_eq!(dialog.controls.len(),1);
+	match SQLITE_X86_PE.get_dialog(ResourceId::Id(1),None) {
+		Err(Error::ResourceNotFound) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+}
+
+#[test]
+fn menus() {
+	use resources::menu::*;
+
+	fn item(flags: u32, id: u32, text: &str, children: Vec<MenuItem>) -> MenuItem {
+		MenuItem{flags:flags,state:0,id:id,text:text.to_owned(),help_id:0,popup:!children.is_empty(),children:children}
+	}
+
+	let mut data=le_words(&[0,0, 0x10],2);
+	push_utf16z(&mut data,"&File");
+	data.extend(le_words(&[0,100],2));
+	push_utf16z(&mut data,"&Open");
+	data.extend(le_words(&[0,0,0],2));
+	data.extend(le_words(&[0x81,101],2));
+	push_utf16z(&mut data,"E&xit");
+	data.extend(le_words(&[0x4090],2));
+	push_utf16z(&mut data,"&Help");
+	data.extend(le_words(&[0x88,102],2));
+	push_utf16z(&mut data,"&About");
+
+	let menu=Menu::parse(&data).unwrap();
+	assert!(!menu.extended);
+	assert_eq!(menu.items,[
+		item(0,0,"&File",vec![item(0,100,"&Open",vec![]),item(0,0,"",vec![]),item(MF_GRAYED,101,"E&xit",vec![])]),
+		item(MF_HELP,0,"&Help",vec![item(MF_CHECKED,102,"&About",vec![])]),
+	]);
+	assert_eq!(menu.items[0].children.iter().map(|i|i.is_separator()).collect::<Vec<_>>(),[false,true,false]);
+	match Menu::parse(&data[..data.len()-16]) {
+		Err(Error::InvalidSize) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+
+	let mut ex=le_words(&[1,4],2);
+	ex.extend(le_words(&[5, 0,0,200],4));
+	push_u16(&mut ex,1);
+	push_utf16z(&mut ex,"&View");
+	pad4(&mut ex);
+	ex.extend(le_words(&[9, 0,8,201],4));
+	push_u16(&mut ex,0);
+	push_utf16z(&mut ex,"&Status");
+	pad4(&mut ex);
+	ex.extend(le_words(&[MFT_SEPARATOR,0,0],4));
+	push_u16(&mut ex,0x80);
+	push_utf16z(&mut ex,"");
+	pad4(&mut ex);
+	ex.extend(le_words(&[0,3,202],4));
+	push_u16(&mut ex,0x80);
+	push_utf16z(&mut ex,"&Last");
+
+	let menu=Menu::parse(&ex).unwrap();
+	assert!(menu.extended);
+	assert_eq!(menu.help_id,5);
+	assert_eq!(menu.items,[
+		MenuItem{flags:0,state:0,id:200,text:"&View".to_owned(),help_id:9,popup:true,children:vec![
+			MenuItem{flags:0,state:8,id:201,text:"&Status".to_owned(),help_id:0,popup:false,children:vec![]},
+			MenuItem{flags:MFT_SEPARATOR,state:0,id:0,text:"".to_owned(),help_id:0,popup:false,children:vec![]},
+		]},
+		MenuItem{flags:0,state:3,id:202,text:"&Last".to_owned(),help_id:0,popup:false,children:vec![]},
+	]);
+	assert!(menu.items[0].children[1].is_separator());
+
+	match Menu::parse(&le_words(&[2,0],2)) {
+		Err(Error::UnsupportedVersion) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+	let nested=le_words(&[0,0].iter().cloned().chain((0..100).flat_map(|_|vec![0x10,0])).collect::<Vec<_>>(),2);
+	match Menu::parse(&nested) {
+		Err(Error::InvalidResource) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+	match SQLITE_X86_PE.get_menu(resources::ResourceId::Id(1),None) {
+		Err(Error::ResourceNotFound) => {},
+		other => panic!("unexpected result: {:?}",other),
+	}
+}
+
+#[test]
+fn rebuild_resources() {
+	use resources::*;
+	use resources::builder::*;
+
+	fn summary(pe: &Pe) -> Vec<(String,String,u16,Vec<u8>)> {
+		pe.get_resources().unwrap().list().unwrap().iter().map(|r|(r.rtype.to_string(),r.name.to_string(),r.lang,r.data.get_data().unwrap().to_owned())).collect()
+	}
+
+	let pe=&*SQLITE_X86_PE;
+	let original=summary(pe);
+	let section_count=pe.get_sections().len();
+
+	// Unchanged resources fit in the existing section
+	let builder=ResourceBuilder::from_resources(&pe.get_resources().unwrap()).unwrap();
+	let image=replace_resources(&SQLITE_X86_BUF,&builder).unwrap();
+	assert_eq!(image.len(),SQLITE_X86_BUF.len());
+	let new=Pe::new(&image).unwrap();
+	assert_eq!(new.get_sections().len(),section_count);
+	assert_eq!(summary(&new),original);
+	assert_eq!(new.get_version_info().unwrap().get_file_version(),Some([3,10,1,0]));
+	assert_eq!(new.get_imports().unwrap().count(),pe.get_imports().unwrap().count());
+	let builder=ResourceBuilder::from_resources(&SQLITE_X64_PE.get_resources().unwrap()).unwrap();
+	assert_eq!(summary(&Pe::new(&replace_resources(&SQLITE_X64_BUF,&builder).unwrap()).unwrap()),summary(&SQLITE_X64_PE));
+
+	// Named types and names come first, sorted case-insensitively
+	let (custom,beta,alpha): (Vec<u16>,Vec<u16>,Vec<u16>)=("CUSTOM".encode_utf16().collect(),"beta".encode_utf16().collect(),"ALPHA".encode_utf16().collect());
+	let mut builder=ResourceBuilder::from_resources(&pe.get_resources().unwrap()).unwrap();
+	assert!(builder.remove(RT_ICON,ResourceId::Id(1),None));
+	assert!(!builder.remove(RT_ICON,ResourceId::Id(1),None));
+	builder.set(ResourceId::Name(&custom),ResourceId::Name(&beta),0,&b"second"[..]);
+	builder.set(ResourceId::Name(&custom),ResourceId::Name(&alpha),0,vec![1,2,3]);
+	builder.set(ResourceId::Name(&custom),ResourceId::Id(7),0,&b"third"[..]);
+	builder.set(RT_MANIFEST,ResourceId::Id(1),1033,TEST_MANIFEST.as_bytes());
+	let image=replace_resources(&SQLITE_X86_BUF,&builder).unwrap();
+	let new=Pe::new(&image).unwrap();
+	assert_eq!(new.get_sections().len(),section_count);
+	let list: Vec<_>=summary(&new).into_iter().map(|(rtype,name,lang,_)|(rtype,name,lang)).collect();
+	assert_eq!(list,[
+		("CUSTOM".to_owned(),"ALPHA".to_owned(),0),("CUSTOM".to_owned(),"beta".to_owned(),0),("CUSTOM".to_owned(),"#7".to_owned(),0),
+		("#14".to_owned(),"#101".to_owned(),1033),("#16".to_owned(),"#1".to_owned(),1033),("#24".to_owned(),"#1".to_owned(),1033),
+	]);
+	assert_eq!(new.find_resource(ResourceId::Name(&custom),ResourceId::Name(&alpha),None).unwrap(),[1,2,3]);
+	assert!(new.get_manifest().unwrap().requires_administrator());
+	let ddir=new.get_directory::<ResourceDirectory>().unwrap();
+	assert_eq!(ddir.virtual_address.get(),0x115000);
+	assert_eq!(builder.build(0x115000).len() as u32,{ddir.size});
+	for resource in new.get_resources().unwrap().list().unwrap() {
+		assert_eq!(resource.data.get_entry().data.get()%8,0);
+	}
+
+	// Resources that share a section with other data go in a new section,
+	// and the other data is kept
+	let mut buf=SQLITE_X86_BUF.clone();
+	let rsrc=&pe.get_sections()[section_count-2];
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,rsrc)+8,0x1400);
+	let other=rsrc.pointer_to_raw_data.get() as usize+0x1300;
+	buf[other..other+10].copy_from_slice(b"other data");
+	let builder=ResourceBuilder::from_resources(&pe.get_resources().unwrap()).unwrap();
+	let image=replace_resources(&buf,&builder).unwrap();
+	let new=Pe::new(&image).unwrap();
+	assert_eq!(new.get_sections().len(),section_count+1);
+	assert_eq!(summary(&new),original);
+	assert_eq!(&image[other..other+10],b"other data");
+
+	// Resources that don't fit go in a new section. Add a certificate table,
+	// debug data and a COFF symbol table after the last section to check
+	// they are moved, and a checksum to check it is cleared.
+	let overlay=b"certificate datadebug datasymbols";
+	let mut buf=SQLITE_X86_BUF.clone();
+	buf.extend_from_slice(overlay);
+	let certificates=pe.get_directory_raw(DirectoryEntry::CertificateTable).unwrap();
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,certificates),SQLITE_X86_BUF.len() as u32);
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,certificates)+4,16);
+	let debug=pe.get_directory_raw(DirectoryEntry::Debug).unwrap();
+	let debug_fp=pe.resolve_rva_slice(debug.virtual_address,debug.size).unwrap().get() as usize;
+	let debug_data=read_u32(&buf,debug_fp+24).unwrap();
+	write_u32(&mut buf,debug_fp+28+24,SQLITE_X86_BUF.len() as u32+16);
+	write_u32(&mut buf,fp_of(&SQLITE_X86_BUF,pe.get_header())+12,SQLITE_X86_BUF.len() as u32+26);
+	let check_sum_fp=match pe.get_optional_header() {
+		PeOptionalHeader::Pe32(oh) => fp_of(&SQLITE_X86_BUF,oh)+64,
+		_ => unreachable!(),
+	};
+	write_u32(&mut buf,check_sum_fp,0x12345678);
+	let big=vec![0x55u8;0x10000];
+	let mut builder=ResourceBuilder::from_resources(&pe.get_resources().unwrap()).unwrap();
+	builder.set(RT_RCDATA,ResourceId::Id(1),0,&big[..]);
+	let image=replace_resources(&buf,&builder).unwrap();
+	let new=Pe::new(&image).unwrap();
+	assert_eq!(new.get_sections().len(),section_count+1);
+	let section=new.get_sections().last().unwrap();
+	assert_eq!(section.name.as_os_str(),OsStr::new(".rsrc"));
+	assert_eq!((section.virtual_address.get(),section.pointer_to_raw_data.get()),(0x11e000,0x116e00));
+	assert_eq!(section.size_of_raw_data%0x200,0);
+	assert_eq!({section.characteristics.bits()},0x40000040);
+	assert_eq!(new.get_directory::<ResourceDirectory>().unwrap().virtual_address.get(),0x11e000);
+	match new.get_optional_header() {
+		PeOptionalHeader::Pe32(oh) => assert_eq!({oh.size_of_image},0x11e000+0x11000),
+		_ => unreachable!(),
+	}
+	assert!(new.find_resource(RT_RCDATA,ResourceId::Id(1),None).unwrap()==&big[..]);
+	assert_eq!(new.find_resource(RT_VERSION,ResourceId::Id(1),None).unwrap().len(),1056);
+	let certificates=new.get_directory_raw(DirectoryEntry::CertificateTable).unwrap().virtual_address.get() as usize;
+	assert_eq!(certificates,0x116e00+section.size_of_raw_data as usize);
+	assert_eq!(&image[certificates..],&overlay[..]);
+	assert_eq!(read_u32(&image,debug_fp+24).unwrap(),debug_data);
+	assert_eq!(read_u32(&image,debug_fp+28+24).unwrap() as usize,certificates+16);
+	assert_eq!(new.get_header().pointer_to_symbol_table.get() as usize,certificates+26);
+	assert_eq!(read_u32(&image,check_sum_fp).unwrap(),0);
+
+	// The new section is the last one, so it can grow
+	builder.set(RT_RCDATA,ResourceId::Id(2),0,&big[..]);
+	let image=replace_resources(&image,&builder).unwrap();
+	let new=Pe::new(&image).unwrap();
+	assert_eq!(new.get_sections().len(),section_count+1);
+	assert!(new.find_resource(RT_RCDATA,ResourceId::Id(2),None).unwrap()==&big[..]);
+	assert!(image.ends_with(overlay));
+	let certificates=image.len()-overlay.len();
+	assert_eq!(new.get_directory_raw(DirectoryEntry::CertificateTable).unwrap().virtual_address.get() as usize,certificates);
+	assert_eq!(read_u32(&image,debug_fp+28+24).unwrap() as usize,certificates+16);
+	assert_eq!(new.get_header().pointer_to_symbol_table.get() as usize,certificates+26);
+
+	// No room for another section header
+	let mut buf=SQLITE_X86_BUF.clone();
+	let table_end=fp_of(&SQLITE_X86_BUF,pe.get_sections().last().unwrap())+size_of::<SectionHeader>();
+	let size_of_headers_fp=match pe.get_optional_header() {
+		PeOptionalHeader::Pe32(oh) => fp_of(&SQLITE_X86_BUF,oh)+60,
+		_ => unreachable!(),
+	};
+	write_u32(&mut buf,size_of_headers_fp,table_end as u32);
+	match replace_resources(&buf,&builder) {
+		Err(Error::NoHeaderSpace) => {},
+		other => panic!("unexpected result: {:?}",other.map(|image|image.len())),
+	}
+}
@@ -11,7 +11,7 @@
 
 #![allow(non_camel_case_types)]
 
-use super::utility::{FP,RVA,CChar,RefSafe,Size4Bytes};
+use super::utility::{FP,RVA,URP,CChar,RefSafe,Size4Bytes};
 
 pub const DOS_SIGNATURE: u16 = 0x5a4d;
 pub const PE_SIGNATURE: u32 = 0x00004550;
@@ -43,6 +43,11 @@ pub enum Machine {
     SH5       = 0x01a8,
     THUMB     = 0x01c2,
     WCEMIPSV2 = 0x0169,
+    RISCV32   = 0x5032,
+    RISCV64   = 0x5064,
+    RISCV128  = 0x5128,
+    LOONGARCH32 = 0x6232,
+    LOONGARCH64 = 0x6264,
 }
 
 #[repr(u16)]
@@ -61,25 +66,78 @@ pub enum Subsystem {
     XBOX                    = 14,
 }
 
-#[repr(u16)]
+/// The meaning of relocation types 5 through 9 depends on the machine type.
+/// Machines that don't define these values get `Unknown`. This includes
+/// ARM64, which has no machine-specific base relocation types.
 #[derive(Copy,Clone,Debug,PartialEq,Eq)]
 pub enum RelocationType {
-	ABSOLUTE        = 0,
-	HIGH            = 1,
-	LOW             = 2,
-	HIGHLOW         = 3,
-	HIGHADJ         = 4,
-	ARM_MOV32A      = 5, // also MIPS_JMPADDR
-	RESERVED1       = 6,
-	ARM_MOV32T      = 7,
-	RESERVED2       = 8,
-	MIPS_JMPADDR16  = 9,
-	DIR64           = 10,
-	RESERVED3       = 11,
-	RESERVED4       = 12,
-	RESERVED5       = 13,
-	RESERVED6       = 14,
-	RESERVED7       = 15,
+	ABSOLUTE,
+	HIGH,
+	LOW,
+	HIGHLOW,
+	HIGHADJ,
+	/// ARM: 5
+	ARM_MOV32A,
+	/// ARM, Thumb: 7
+	ARM_MOV32T,
+	/// MIPS: 5
+	MIPS_JMPADDR,
+	/// MIPS: 9
+	MIPS_JMPADDR16,
+	/// Itanium: 9
+	IA64_IMM64,
+	/// RISC-V: 5
+	RISCV_HIGH20,
+	/// RISC-V: 7
+	RISCV_LOW12I,
+	/// RISC-V: 8
+	RISCV_LOW12S,
+	/// LoongArch: 8
+	LOONGARCH_MARK_LA,
+	DIR64,
+	Unknown(u8),
+}
+
+impl RelocationType {
+	pub fn from_raw(machine: Machine, raw: u8) -> RelocationType {
+		use self::Machine::*;
+		use self::RelocationType::*;
+		match (raw,machine) {
+			(0,_) => ABSOLUTE,
+			(1,_) => HIGH,
+			(2,_) => LOW,
+			(3,_) => HIGHLOW,
+			(4,_) => HIGHADJ,
+			(10,_) => DIR64,
+			(5,ARM) | (5,THUMB) | (5,ARMNT) => ARM_MOV32A,
+			(7,ARM) | (7,THUMB) | (7,ARMNT) => ARM_MOV32T,
+			(5,R4000) | (5,WCEMIPSV2) | (5,MIPS16) | (5,MIPSFPU) | (5,MIPSFPU16) => MIPS_JMPADDR,
+			(9,R4000) | (9,WCEMIPSV2) | (9,MIPS16) | (9,MIPSFPU) | (9,MIPSFPU16) => MIPS_JMPADDR16,
+			(9,IA64) => IA64_IMM64,
+			(5,RISCV32) | (5,RISCV64) | (5,RISCV128) => RISCV_HIGH20,
+			(7,RISCV32) | (7,RISCV64) | (7,RISCV128) => RISCV_LOW12I,
+			(8,RISCV32) | (8,RISCV64) | (8,RISCV128) => RISCV_LOW12S,
+			(8,LOONGARCH32) | (8,LOONGARCH64) => LOONGARCH_MARK_LA,
+			(raw,_) => Unknown(raw),
+		}
+	}
+
+	pub fn raw(self) -> u8 {
+		use self::RelocationType::*;
+		match self {
+			ABSOLUTE => 0,
+			HIGH => 1,
+			LOW => 2,
+			HIGHLOW => 3,
+			HIGHADJ => 4,
+			ARM_MOV32A | MIPS_JMPADDR | RISCV_HIGH20 => 5,
+			ARM_MOV32T | RISCV_LOW12I => 7,
+			RISCV_LOW12S | LOONGARCH_MARK_LA => 8,
+			MIPS_JMPADDR16 | IA64_IMM64 => 9,
+			DIR64 => 10,
+			Unknown(raw) => raw,
+		}
+	}
 }
 
 pub mod image_characteristics {
@@ -332,6 +390,262 @@ unsafe impl RefSafe for ExportDirectory {}
 pub struct RawExportAddress(pub RVA<()>);
 unsafe impl RefSafe for RawExportAddress {}
 
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct ImportDescriptor {
+    pub import_lookup_table: RVA<()>, // RVA<[ThunkData32]> or RVA<[ThunkData64]>
+    pub time_date_stamp: u32,
+    pub forwarder_chain: u32,
+    pub name: RVA<[CChar]>,
+    pub import_address_table: RVA<()>, // RVA<[ThunkData32]> or RVA<[ThunkData64]>
+}
+unsafe impl RefSafe for ImportDescriptor {}
+
+impl ImportDescriptor {
+	pub fn is_null(&self) -> bool {
+		self.import_lookup_table.get()==0 && self.time_date_stamp==0 && self.forwarder_chain==0
+			&& self.name.get()==0 && self.import_address_table.get()==0
+	}
+}
+
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct DelayImportDescriptor {
+    pub attributes: u32,
+    // The following are VAs instead of RVAs if the RVA attribute is not set
+    pub name: RVA<[CChar]>,
+    pub module_handle: RVA<()>,
+    pub delay_import_address_table: RVA<()>, // RVA<[ThunkData32]> or RVA<[ThunkData64]>
+    pub delay_import_name_table: RVA<()>, // RVA<[ThunkData32]> or RVA<[ThunkData64]>
+    pub bound_delay_import_table: RVA<()>, // RVA<[ThunkData32]> or RVA<[ThunkData64]>
+    pub unload_delay_import_table: RVA<()>, // RVA<[ThunkData32]> or RVA<[ThunkData64]>
+    pub time_date_stamp: u32,
+}
+unsafe impl RefSafe for DelayImportDescriptor {}
+
+pub const DELAY_IMPORT_ATTRIBUTE_RVA: u32 = 0x1;
+
+impl DelayImportDescriptor {
+	pub fn is_null(&self) -> bool {
+		self.attributes==0 && self.name.get()==0 && self.module_handle.get()==0
+			&& self.delay_import_address_table.get()==0 && self.delay_import_name_table.get()==0
+			&& self.bound_delay_import_table.get()==0 && self.unload_delay_import_table.get()==0
+			&& self.time_date_stamp==0
+	}
+
+	/// Descriptors generated by old linkers contain VAs instead of RVAs
+	pub fn is_rva_based(&self) -> bool {
+		self.attributes&DELAY_IMPORT_ATTRIBUTE_RVA!=0
+	}
+}
+
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct BoundImportDescriptor {
+    pub time_date_stamp: u32,
+    pub offset_module_name: u16, // relative to the start of the directory
+    pub number_of_module_forwarder_refs: u16,
+}
+unsafe impl RefSafe for BoundImportDescriptor {}
+
+impl BoundImportDescriptor {
+	pub fn is_null(&self) -> bool {
+		self.time_date_stamp==0 && self.offset_module_name==0 && self.number_of_module_forwarder_refs==0
+	}
+}
+
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct BoundForwarderRef {
+    pub time_date_stamp: u32,
+    pub offset_module_name: u16, // relative to the start of the directory
+    pub reserved: u16,
+}
+unsafe impl RefSafe for BoundForwarderRef {}
+
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Thunk {
+	Ordinal(u16),
+	/// Points to a 16-bit hint, followed by a NULL-terminated name
+	HintName(RVA<u16>),
+}
+
+#[repr(packed)]
+#[derive(Copy,Clone,Debug,PartialEq,Eq,Default)]
+pub struct ThunkData32(pub u32);
+unsafe impl RefSafe for ThunkData32 {}
+
+impl ThunkData32 {
+	/// Returns `None` for the NULL entry terminating a lookup table
+	pub fn decode(self) -> Option<Thunk> {
+		let v=self.0;
+		if v==0 {
+			None
+		} else if v&0x80000000!=0 {
+			Some(Thunk::Ordinal(v as u16))
+		} else {
+			Some(Thunk::HintName(RVA::new(v&0x7fffffff)))
+		}
+	}
+}
+
+#[repr(packed)]
+#[derive(Copy,Clone,Debug,PartialEq,Eq,Default)]
+pub struct ThunkData64(pub u64);
+unsafe impl RefSafe for ThunkData64 {}
+
+impl ThunkData64 {
+	/// Returns `None` for the NULL entry terminating a lookup table
+	pub fn decode(self) -> Option<Thunk> {
+		let v=self.0;
+		if v==0 {
+			None
+		} else if v&0x8000000000000000!=0 {
+			Some(Thunk::Ordinal(v as u16))
+		} else {
+			Some(Thunk::HintName(RVA::new((v as u32)&0x7fffffff)))
+		}
+	}
+}
+
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct ResourceDirectory {
+    pub characteristics: u32,
+    pub time_date_stamp: u32,
+    pub major_version: u16,
+    pub minor_version: u16,
+    pub number_of_named_entries: u16,
+    pub number_of_id_entries: u16,
+}
+unsafe impl RefSafe for ResourceDirectory {}
+
+pub const RESOURCE_NAME_IS_STRING: u32 = 0x80000000;
+pub const RESOURCE_DATA_IS_DIRECTORY: u32 = 0x80000000;
+
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct ResourceDirectoryEntry {
+    pub name: u32, // ID, or offset of the name relative to the start of the resource directory
+    pub offset: u32, // relative to the start of the resource directory
+}
+unsafe impl RefSafe for ResourceDirectoryEntry {}
+
+impl ResourceDirectoryEntry {
+	pub fn is_named(&self) -> bool {
+		self.name&RESOURCE_NAME_IS_STRING!=0
+	}
+
+	pub fn is_directory(&self) -> bool {
+		self.offset&RESOURCE_DATA_IS_DIRECTORY!=0
+	}
+}
+
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct ResourceDataEntry {
+    pub data: RVA<[u8]>,
+    pub size: u32,
+    pub code_page: u32,
+    pub reserved: u32,
+}
+unsafe impl RefSafe for ResourceDataEntry {}
+
+/// The header of `RT_GROUP_ICON` and `RT_GROUP_CURSOR` resources, as well as
+/// .ico and .cur files
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct IconDir {
+    pub reserved: u16,
+    pub icon_type: u16, // 1 for icons, 2 for cursors
+    pub count: u16,
+}
+unsafe impl RefSafe for IconDir {}
+
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct GroupIconDirEntry {
+    pub width: u8,
+    pub height: u8,
+    pub color_count: u8,
+    pub reserved: u8,
+    pub planes: u16,
+    pub bit_count: u16,
+    pub bytes_in_res: u32,
+    pub id: u16,
+}
+unsafe impl RefSafe for GroupIconDirEntry {}
+
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct GroupCursorDirEntry {
+    pub width: u16,
+    pub height: u16, // twice the cursor height, for the image and the mask
+    pub planes: u16,
+    pub bit_count: u16,
+    pub bytes_in_res: u32,
+    pub id: u16,
+}
+unsafe impl RefSafe for GroupCursorDirEntry {}
+
+/// MESSAGE_RESOURCE_DATA, without the blocks
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct MessageResourceData {
+    pub number_of_blocks: u32,
+}
+unsafe impl RefSafe for MessageResourceData {}
+
+/// MESSAGE_RESOURCE_BLOCK
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct MessageResourceBlock {
+    pub low_id: u32,
+    pub high_id: u32, // inclusive
+    pub offset_to_entries: u32,
+}
+unsafe impl RefSafe for MessageResourceBlock {}
+
+pub const MESSAGE_RESOURCE_ANSI: u16 = 0x0000;
+pub const MESSAGE_RESOURCE_UNICODE: u16 = 0x0001;
+pub const MESSAGE_RESOURCE_UTF8: u16 = 0x0002;
+
+pub const FIXED_FILE_INFO_SIGNATURE: u32 = 0xfeef04bd;
+
+/// VS_FIXEDFILEINFO
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct FixedFileInfo {
+    pub signature: u32,
+    pub struc_version: u32,
+    pub file_version_ms: u32,
+    pub file_version_ls: u32,
+    pub product_version_ms: u32,
+    pub product_version_ls: u32,
+    pub file_flags_mask: u32,
+    pub file_flags: u32,
+    pub file_os: u32,
+    pub file_type: u32,
+    pub file_subtype: u32,
+    pub file_date_ms: u32,
+    pub file_date_ls: u32,
+}
+unsafe impl RefSafe for FixedFileInfo {}
+
+pub mod file_flags {
+    bitflags! {
+        #[repr(packed)]
+        flags FileFlags: u32 {
+            const DEBUG        = 0x01,
+            const PRERELEASE   = 0x02,
+            const PATCHED      = 0x04,
+            const PRIVATEBUILD = 0x08,
+            const INFOINFERRED = 0x10,
+            const SPECIALBUILD = 0x20,
+        }
+    }
+}
+
 #[repr(packed)]
 #[derive(Clone, Debug)]
 pub struct RelocationBlock {
@@ -350,9 +664,220 @@ impl Relocation {
 		if offset>0xfff {
 			panic!("Invalid relocation offset");
 		}
-		Relocation(offset&0xfff | ((rtype as u16)<<12))
+		if rtype.raw()>0xf {
+			panic!("Invalid relocation type");
+		}
+		Relocation(offset&0xfff | ((rtype.raw() as u16)<<12))
 	}
-	pub fn decode(self) -> (RelocationType,u16) {
-		(unsafe{::std::mem::transmute(self.0>>12)},self.0&0xfff)
+	pub fn decode(self, machine: Machine) -> (RelocationType,u16) {
+		(RelocationType::from_raw(machine,(self.0>>12) as u8),self.0&0xfff)
 	}
 }
+
+#[repr(packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LoadConfigCodeIntegrity {
+    pub flags: u16,
+    pub catalog: u16,
+    pub catalog_offset: u32,
+    pub reserved: u32,
+}
+unsafe impl RefSafe for LoadConfigCodeIntegrity {}
+
+/// Fields past `size` bytes are not present in the image
+#[repr(packed)]
+#[derive(Clone, Debug, Default)]
+pub struct LoadConfigDirectory32 {
+    pub size: u32,
+    pub time_date_stamp: u32,
+    pub major_version: u16,
+    pub minor_version: u16,
+    pub global_flags_clear: u32,
+    pub global_flags_set: u32,
+    pub critical_section_default_timeout: u32,
+    pub de_commit_free_block_threshold: u32,
+    pub de_commit_total_free_threshold: u32,
+    pub lock_prefix_table: u32,
+    pub maximum_allocation_size: u32,
+    pub virtual_memory_threshold: u32,
+    pub process_heap_flags: u32,
+    pub process_affinity_mask: u32,
+    pub csd_version: u16,
+    pub dependent_load_flags: u16,
+    pub edit_list: u32,
+    pub security_cookie: u32,
+    pub se_handler_table: u32,
+    pub se_handler_count: u32,
+    pub guard_cf_check_function_pointer: u32,
+    pub guard_cf_dispatch_function_pointer: u32,
+    pub guard_cf_function_table: u32,
+    pub guard_cf_function_count: u32,
+    pub guard_flags: u32,
+    pub code_integrity: LoadConfigCodeIntegrity,
+    pub guard_address_taken_iat_entry_table: u32,
+    pub guard_address_taken_iat_entry_count: u32,
+    pub guard_long_jump_target_table: u32,
+    pub guard_long_jump_target_count: u32,
+    pub dynamic_value_reloc_table: u32,
+    pub chpe_metadata_pointer: u32,
+    pub guard_rf_failure_routine: u32,
+    pub guard_rf_failure_routine_function_pointer: u32,
+    pub dynamic_value_reloc_table_offset: u32,
+    pub dynamic_value_reloc_table_section: u16,
+    pub reserved2: u16,
+    pub guard_rf_verify_stack_pointer_function_pointer: u32,
+    pub hot_patch_table_offset: u32,
+    pub reserved3: u32,
+    pub enclave_configuration_pointer: u32,
+    pub volatile_metadata_pointer: u32,
+    pub guard_eh_continuation_table: u32,
+    pub guard_eh_continuation_count: u32,
+    pub guard_xfg_check_function_pointer: u32,
+    pub guard_xfg_dispatch_function_pointer: u32,
+    pub guard_xfg_table_dispatch_function_pointer: u32,
+    pub cast_guard_os_determined_failure_mode: u32,
+    pub guard_memcpy_function_pointer: u32,
+}
+unsafe impl RefSafe for LoadConfigDirectory32 {}
+
+/// Fields past `size` bytes are not present in the image
+#[repr(packed)]
+#[derive(Clone, Debug, Default)]
+pub struct LoadConfigDirectory64 {
+    pub size: u32,
+    pub time_date_stamp: u32,
+    pub major_version: u16,
+    pub minor_version: u16,
+    pub global_flags_clear: u32,
+    pub global_flags_set: u32,
+    pub critical_section_default_timeout: u32,
+    pub de_commit_free_block_threshold: u64,
+    pub de_commit_total_free_threshold: u64,
+    pub lock_prefix_table: u64,
+    pub maximum_allocation_size: u64,
+    pub virtual_memory_threshold: u64,
+    pub process_affinity_mask: u64,
+    pub process_heap_flags: u32,
+    pub csd_version: u16,
+    pub dependent_load_flags: u16,
+    pub edit_list: u64,
+    pub security_cookie: u64,
+    pub se_handler_table: u64,
+    pub se_handler_count: u64,
+    pub guard_cf_check_function_pointer: u64,
+    pub guard_cf_dispatch_function_pointer: u64,
+    pub guard_cf_function_table: u64,
+    pub guard_cf_function_count: u64,
+    pub guard_flags: u32,
+    pub code_integrity: LoadConfigCodeIntegrity,
+    pub guard_address_taken_iat_entry_table: u64,
+    pub guard_address_taken_iat_entry_count: u64,
+    pub guard_long_jump_target_table: u64,
+    pub guard_long_jump_target_count: u64,
+    pub dynamic_value_reloc_table: u64,
+    pub chpe_metadata_pointer: u64,
+    pub guard_rf_failure_routine: u64,
+    pub guard_rf_failure_routine_function_pointer: u64,
+    pub dynamic_value_reloc_table_offset: u32,
+    pub dynamic_value_reloc_table_section: u16,
+    pub reserved2: u16,
+    pub guard_rf_verify_stack_pointer_function_pointer: u64,
+    pub hot_patch_table_offset: u32,
+    pub reserved3: u32,
+    pub enclave_configuration_pointer: u64,
+    pub volatile_metadata_pointer: u64,
+    pub guard_eh_continuation_table: u64,
+    pub guard_eh_continuation_count: u64,
+    pub guard_xfg_check_function_pointer: u64,
+    pub guard_xfg_dispatch_function_pointer: u64,
+    pub guard_xfg_table_dispatch_function_pointer: u64,
+    pub cast_guard_os_determined_failure_mode: u64,
+    pub guard_memcpy_function_pointer: u64,
+}
+unsafe impl RefSafe for LoadConfigDirectory64 {}
+
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct DynamicRelocationTable {
+    pub version: u32,
+    pub size: u32, // not including this header
+}
+unsafe impl RefSafe for DynamicRelocationTable {}
+
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct DynamicRelocation32 {
+    pub symbol: u32,
+    pub base_reloc_size: u32,
+}
+unsafe impl RefSafe for DynamicRelocation32 {}
+
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct DynamicRelocation64 {
+    pub symbol: u64,
+    pub base_reloc_size: u32,
+}
+unsafe impl RefSafe for DynamicRelocation64 {}
+
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct DynamicRelocation32V2 {
+    pub header_size: u32, // including this structure
+    pub fixup_info_size: u32,
+    pub symbol: u32,
+    pub symbol_group: u32,
+    pub flags: u32,
+}
+unsafe impl RefSafe for DynamicRelocation32V2 {}
+
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct DynamicRelocation64V2 {
+    pub header_size: u32, // including this structure
+    pub fixup_info_size: u32,
+    pub symbol: u64,
+    pub symbol_group: u32,
+    pub flags: u32,
+}
+unsafe impl RefSafe for DynamicRelocation64V2 {}
+
+/// Well-known values of the `symbol` field of dynamic relocations. For other
+/// values, the symbol is the VA of the value stored by each relocation.
+pub mod dynamic_relocation_symbol {
+    pub const GUARD_RF_PROLOGUE: u64 = 1;
+    pub const GUARD_RF_EPILOGUE: u64 = 2;
+    pub const GUARD_IMPORT_CONTROL_TRANSFER: u64 = 3;
+    pub const GUARD_INDIR_CONTROL_TRANSFER: u64 = 4;
+    pub const GUARD_SWITCHTABLE_BRANCH: u64 = 5;
+    pub const ARM64X: u64 = 6;
+    pub const FUNCTION_OVERRIDE: u64 = 7;
+    pub const ARM64_KERNEL_IMPORT_CALL_TRANSFER: u64 = 8;
+}
+
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct FunctionOverrideDynamicRelocation {
+    pub original_rva: RVA<()>,
+    pub bdd_offset: u32, // relative to the BDD info
+    pub rva_size: u32, // size of the override RVAs that follow
+    pub base_reloc_size: u32, // size of the base relocations that follow the RVAs
+}
+unsafe impl RefSafe for FunctionOverrideDynamicRelocation {}
+
+#[repr(packed)]
+#[derive(Clone, Debug)]
+pub struct BddInfo {
+    pub version: u32,
+    pub bdd_size: u32,
+}
+unsafe impl RefSafe for BddInfo {}
+
+#[repr(packed)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BddNode {
+    pub left: u16,
+    pub right: u16,
+    pub value: u32,
+}
+unsafe impl RefSafe for BddNode {}
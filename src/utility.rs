@@ -127,6 +127,13 @@ define_urp!{pub struct RVA<T>;}
 #[derive(Clone, Copy, Debug)]
 pub struct CChar(u8);
 
+impl CChar {
+	#[inline]
+	pub fn get(self) -> u8 {
+		self.0
+	}
+}
+
 trait NullTerminatedStr {
 	fn null_terminated(&self) -> Option<&Self>;
 }
@@ -188,6 +195,23 @@ pub enum Error {
 	SymbolNotFound,
 	/// The requested ordinal does not exist in the export table, this probably indicates a malformed file
 	ExportNotFound,
+	/// A forwarder string is not of the form `module.symbol` or `module.#ordinal`
+	InvalidForwarder,
+	/// The data is in a format version that is not supported
+	UnsupportedVersion,
+	/// A relocation of a type that can't be applied was found
+	UnsupportedRelocation,
+	/// The image has no base relocations, so it can't be moved
+	NotRelocatable,
+	/// The image base is not valid for this type of image
+	InvalidImageBase,
+	/// The resource directory is not a tree of type, name and language levels,
+	/// or a resource is malformed
+	InvalidResource,
+	/// The requested resource does not exist in the resource directory
+	ResourceNotFound,
+	/// There is no room in the headers for another section header
+	NoHeaderSpace,
 	Io(IoError),
 }
 
@@ -199,6 +223,62 @@ impl From<IoError> for Error {
 	}
 }
 
+fn read_le(data: &[u8], pos: usize, size: usize) -> Result<u64> {
+	let bytes=try!(pos.checked_add(size).and_then(|end|data.get(pos..end)).ok_or(Error::InvalidSize));
+	Ok(bytes.iter().rev().fold(0,|value,&b|value<<8|b as u64))
+}
+
+fn write_le(data: &mut [u8], pos: usize, size: usize, value: u64) {
+	for (i,b) in data[pos..pos+size].iter_mut().enumerate() {
+		*b=(value>>(i*8)) as u8;
+	}
+}
+
+/// Little-endian reads of possibly unaligned data. Returns `InvalidSize` if
+/// the data is too short.
+pub fn read_u16(data: &[u8], pos: usize) -> Result<u16> {
+	read_le(data,pos,2).map(|v|v as u16)
+}
+
+pub fn read_u32(data: &[u8], pos: usize) -> Result<u32> {
+	read_le(data,pos,4).map(|v|v as u32)
+}
+
+pub fn read_u64(data: &[u8], pos: usize) -> Result<u64> {
+	read_le(data,pos,8)
+}
+
+/// Big-endian read, for the few formats that use it
+pub fn read_u16_be(data: &[u8], pos: usize) -> Result<u16> {
+	read_le(data,pos,2).map(|v|(v as u16).swap_bytes())
+}
+
+/// Little-endian writes of possibly unaligned data. Panics if the data is
+/// too short.
+pub fn write_u16(data: &mut [u8], pos: usize, value: u16) {
+	write_le(data,pos,2,value as u64)
+}
+
+pub fn write_u32(data: &mut [u8], pos: usize, value: u32) {
+	write_le(data,pos,4,value as u64)
+}
+
+pub fn write_u64(data: &mut [u8], pos: usize, value: u64) {
+	write_le(data,pos,8,value)
+}
+
+pub fn push_u16(out: &mut Vec<u8>, value: u16) {
+	let len=out.len();
+	out.resize(len+2,0);
+	write_u16(out,len,value);
+}
+
+pub fn push_u32(out: &mut Vec<u8>, value: u32) {
+	let len=out.len();
+	out.resize(len+4,0);
+	write_u32(out,len,value);
+}
+
 pub trait FPRef<'data> {
 	fn ref_at<T: RefSafe>(&'data self, fp: FP<T>) -> Result<&'data T>;
 	fn ref_slice_at<T: RefSafe>(&'data self, fp: FP<[T]>, count: u32) -> Result<&'data [T]>;